@@ -11,12 +11,46 @@ pub struct RaceConfig {
     pub slot_fp_offset: usize,
     pub slot_len_offset: usize,
     pub header_local_depth_offset: usize,
+    pub header_migration_offset: usize,
     pub header_suffix_offset: usize,
     pub directory_lock_offset: usize,
     pub directory_localdepth_offset: usize,
     pub max_entry_num: usize,
     pub entry_size: usize,
     pub max_try_lock_times: usize,
+    pub merge_threshold_percent: usize,
+    pub min_local_depth: u8,
+    pub migration_step_group_num: usize,
+    pub max_try_displace_times: usize,
+    pub resize_fill_percent: usize,
+    pub resize_interval_ms: u64,
+    pub resize_sample_group_num: usize,
+    pub thread_stack_size: usize,
+    pub max_client_num: usize,
+    pub reclaim_batch_num: usize,
+    pub lock_lease_ms: u64,
+    pub max_retry_times: usize,
+    pub retry_spin_times: usize,
+    pub retry_base_backoff_us: u64,
+    pub retry_max_backoff_us: u64,
+    pub retry_deadline_ms: u64,
+    pub shard_virtual_node_num: usize,
+    pub replica_key_lock_num: usize,
+    pub sim_max_steps: usize,
+    pub sim_max_shrink_runs: usize,
+    pub slab_min_size: usize,
+    pub slab_max_size: usize,
+    pub slab_chunk_size: usize,
+    pub slab_cache_num: usize,
+    pub default_numa_node: i32,
+    pub huge_page_size: usize,
+    pub region_size: usize,
+    pub compact_used_percent: usize,
+    pub evict_batch_num: usize,
+    pub evict_fill_percent: usize,
+    pub slot_inline_tag: u8,
+    pub inline_data_size: usize,
+    pub inline_len_bits: usize,
 }
 
 pub const CONFIG: RaceConfig = RaceConfig {
@@ -32,10 +66,44 @@ pub const CONFIG: RaceConfig = RaceConfig {
     slot_fp_offset: 0,
     slot_len_offset: 1,
     header_local_depth_offset: 0,
-    header_suffix_offset: 1,
+    header_migration_offset: 1,
+    header_suffix_offset: 2,
     directory_lock_offset: 0,
     directory_localdepth_offset: 1,
     max_entry_num: 1 << 16,
     entry_size: 64,
     max_try_lock_times: 5,
+    merge_threshold_percent: 25,
+    min_local_depth: 1,
+    migration_step_group_num: 64,
+    max_try_displace_times: 16,
+    resize_fill_percent: 60,
+    resize_interval_ms: 10,
+    resize_sample_group_num: 64,
+    thread_stack_size: 16 << 20,
+    max_client_num: 255,
+    reclaim_batch_num: 64,
+    lock_lease_ms: 1000,
+    max_retry_times: 1 << 16,
+    retry_spin_times: 16,
+    retry_base_backoff_us: 1,
+    retry_max_backoff_us: 1000,
+    retry_deadline_ms: 10000,
+    shard_virtual_node_num: 128,
+    replica_key_lock_num: 64,
+    sim_max_steps: 1 << 20,
+    sim_max_shrink_runs: 200,
+    slab_min_size: 16,
+    slab_max_size: 4096,
+    slab_chunk_size: 64 << 10,
+    slab_cache_num: 64,
+    default_numa_node: 0,
+    huge_page_size: 2 << 20,
+    region_size: 16 << 20,
+    compact_used_percent: 50,
+    evict_batch_num: 16,
+    evict_fill_percent: 90,
+    slot_inline_tag: 0x80,
+    inline_data_size: 7,
+    inline_len_bits: 3,
 };
@@ -1,10 +1,15 @@
 use crate::cfg::config::CONFIG;
 
-use super::numa::Numa;
+use super::numa::{HugePages, Numa};
+use super::region::{MemoryRegion, Regions};
 use std::{
-    borrow::BorrowMut,
-    ptr::null,
-    sync::{atomic::fence, Arc, Mutex},
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ptr::null_mut,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
 };
 
 extern "C" {
@@ -12,243 +17,664 @@ extern "C" {
     pub fn memset(str: *mut u8, c: i32, n: u32);
 }
 
-struct Free {
-    ptr: *mut u8,
-    size: usize,
-    next: Option<Arc<Mutex<Free>>>,
+// the nodes memory is taken from, chosen for every MemPool
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Placement {
+    Node(i32),
+    // chunks and large blocks take the nodes in turn
+    Interleave,
+    // subtables and directories on one node, kv blocks on another
+    Split { table: i32, kv: i32 },
 }
 
-pub struct Page {
-    used_size: usize,
-    tot_size: usize,
-    start_ptr: *mut u8,
-    free_list: Option<Arc<Mutex<Free>>>,
+// what a block holds, it decides the node under a split placement
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AllocKind {
+    // subtables and directories, they take whole pages
+    Table,
+    Kv,
 }
 
-pub struct MemoryManager {
-    pub pages: Vec<Page>,
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStats {
+    // bytes taken from every node
+    pub node_bytes: Vec<usize>,
+    pub huge_page_bytes: usize,
+    // advised to be huge pages, the kernel may still back them with small pages
+    pub transparent_huge_page_bytes: usize,
+    // empty chunks and freed large blocks whose pages are given back to the os
+    pub released_bytes: usize,
 }
 
-impl MemoryManager {
-    pub fn new() -> MemoryManager {
-        MemoryManager { pages: Vec::new() }
-    }
+// the rest of the last region of a node, large blocks and chunks are carved from it
+struct Region {
+    next: *mut u8,
+    end: *mut u8,
+}
 
-    fn alloc_new_page(&mut self, num: usize) {
-        let ptr = Numa::numa_alloc_onnode(num * CONFIG.page_size, 0);
-        if CONFIG.enable_mm_debug {
-            print!("[alloc] alloc new page: {}\n", num);
-        }
-        for i in 0..num * CONFIG.page_size {
-            unsafe {
-                (*ptr.offset(i as isize)) = 0;
-            }
-        }
-        self.pages.push(Page {
-            used_size: 0,
-            tot_size: num * CONFIG.page_size,
-            start_ptr: ptr,
-            free_list: Some(Arc::new(Mutex::new(Free {
-                ptr,
-                size: num * CONFIG.page_size,
-                next: None,
-            }))),
-        });
-    }
+struct Chunk {
+    free: Vec<*mut u8>,
+    block_num: usize,
+    // nothing is handed out of it while a compaction moves its blocks away
+    evacuating: bool,
+}
 
-    pub fn find_from_alloced_pages(&mut self, mut size: usize) -> Option<*mut u8> {
-        size = (size & (!(CONFIG.align_bytes - 1)))
-            + ((size & (CONFIG.align_bytes - 1)) != 0) as usize * CONFIG.align_bytes;
-        for page in self.pages.iter_mut() {
-            if page.tot_size - page.used_size >= size {
-                if CONFIG.enable_mm_debug {
-                    print!(
-                        "[malloc] find from alloced pages: size: {}, tot_size: {}, used_size: {}\n",
-                        size, page.tot_size, page.used_size
-                    );
-                }
-                let mut free_list = page.free_list.clone();
-                let mut free = free_list.unwrap().clone();
-                let mut prev = free.clone();
-                let mut is_head = true;
-                while free.lock().unwrap().size < size {
-                    is_head = false;
-                    prev = free.clone();
-                    if free.lock().unwrap().next.is_none() {
-                        break;
-                    }
-                    let tmp = free.clone();
-                    free = tmp.lock().unwrap().next.clone().unwrap();
-                }
-                if free.lock().unwrap().size < size {
-                    continue;
+// blocks of one size, carved from chunks of slab_chunk_size; an empty chunk gives its pages
+// back to the os and is kept for any class
+struct SizeClass {
+    size: usize,
+    chunks: BTreeMap<usize, Chunk>,
+    // chunks with free blocks to hand out, the lowest first so that the others drain
+    partial: BTreeSet<usize>,
+}
+
+impl SizeClass {
+    fn alloc(&mut self, central: &Central) -> *mut u8 {
+        let base = match self.partial.first() {
+            Some(base) => *base,
+            None => {
+                let chunk = central.alloc_chunk();
+                if chunk.is_null() {
+                    return null_mut();
                 }
                 if CONFIG.enable_mm_debug {
-                    print!(
-                        "[malloc] get free: {}, is_head: {}\n",
-                        free.lock().unwrap().size,
-                        is_head
-                    );
-                }
-                if free.lock().unwrap().size == size {
-                    if is_head {
-                        page.free_list = free.lock().unwrap().next.clone();
-                    } else {
-                        let new_next = free.lock().unwrap().next.clone();
-                        prev.lock().unwrap().next = new_next;
-                    }
-                    page.used_size += size;
-                    return Some(free.lock().unwrap().ptr);
-                } else {
-                    let new_ptr = unsafe { free.clone().lock().unwrap().ptr.offset(size as isize) };
-                    let new_size = free.clone().lock().unwrap().size - size;
-                    let new_next = free.clone().lock().unwrap().next.clone();
-                    let new_free = Arc::new(Mutex::new(Free {
-                        ptr: new_ptr,
-                        size: new_size,
-                        next: new_next,
-                    }));
-                    if is_head {
-                        page.free_list = Some(new_free.clone());
-                    } else {
-                        prev.lock().unwrap().next = Some(new_free.clone());
-                    }
-                    page.used_size += size;
-                    return Some(free.lock().unwrap().ptr);
+                    println!("[alloc] alloc new chunk for size class {}", self.size);
                 }
+                let block_num = CONFIG.slab_chunk_size / self.size;
+                self.chunks.insert(
+                    chunk as usize,
+                    Chunk {
+                        free: (0..block_num)
+                            .rev()
+                            .map(|i| chunk.wrapping_add(i * self.size))
+                            .collect(),
+                        block_num,
+                        evacuating: false,
+                    },
+                );
+                self.partial.insert(chunk as usize);
+                chunk as usize
             }
+        };
+        let chunk = self.chunks.get_mut(&base).unwrap();
+        let ptr = chunk.free.pop().unwrap();
+        if chunk.free.is_empty() {
+            self.partial.remove(&base);
         }
-        None
+        ptr
     }
 
-    pub fn merge(page: &mut Page) {
-        let mut free_list = page.free_list.clone();
-        if free_list.is_none() {
+    fn free(&mut self, ptr: *mut u8, central: &Central) {
+        let (base, chunk) = self.chunks.range_mut(..=ptr as usize).next_back().unwrap();
+        let base = *base;
+        chunk.free.push(ptr);
+        if chunk.evacuating {
             return;
         }
-        let mut free = free_list.unwrap().clone();
-        let mut prev = free.clone();
-        if free.lock().unwrap().next.is_some() {
-            free = prev.lock().unwrap().next.clone().unwrap();
-        } else {
+        // the last chunk of the class is kept, so that a class in use does not thrash
+        if chunk.free.len() == chunk.block_num && self.chunks.len() > 1 {
+            self.chunks.remove(&base);
+            self.partial.remove(&base);
+            central.release_chunk(base as *mut u8);
             return;
         }
-        while prev.lock().unwrap().next.is_some() {
-            free = prev.lock().unwrap().next.clone().unwrap();
-            let prev_size = prev.lock().unwrap().size;
-            if free.lock().unwrap().ptr
-                == unsafe { prev.lock().unwrap().ptr.offset(prev_size as isize) }
-            {
-                prev.lock().unwrap().size += free.lock().unwrap().size;
-                prev.lock().unwrap().next = free.lock().unwrap().next.clone();
+        self.partial.insert(base);
+    }
+
+    // num blocks laid out one after another in fresh chunks, fewer if the node is out of memory
+    fn alloc_fresh(&mut self, central: &Central, num: usize) -> Vec<*mut u8> {
+        let block_num = CONFIG.slab_chunk_size / self.size;
+        let mut blocks = Vec::with_capacity(num);
+        while blocks.len() < num {
+            let chunk = central.alloc_chunk();
+            if chunk.is_null() {
+                break;
+            }
+            let taken = block_num.min(num - blocks.len());
+            blocks.extend((0..taken).map(|i| chunk.wrapping_add(i * self.size)));
+            self.chunks.insert(
+                chunk as usize,
+                Chunk {
+                    free: (taken..block_num)
+                        .rev()
+                        .map(|i| chunk.wrapping_add(i * self.size))
+                        .collect(),
+                    block_num,
+                    evacuating: false,
+                },
+            );
+            if taken < block_num {
+                self.partial.insert(chunk as usize);
+            }
+        }
+        blocks
+    }
+
+    // chunks with less than max_used_percent of their blocks in use
+    fn evacuate(&mut self, max_used_percent: usize) -> usize {
+        let mut evacuated_num = 0;
+        for (base, chunk) in self.chunks.iter_mut() {
+            let used_num = chunk.block_num - chunk.free.len();
+            if used_num > 0 && used_num * 100 < chunk.block_num * max_used_percent {
+                chunk.evacuating = true;
+                self.partial.remove(base);
+                evacuated_num += 1;
+            }
+        }
+        evacuated_num
+    }
+
+    fn finish_evacuation(&mut self, central: &Central) {
+        let mut empty = Vec::new();
+        for (base, chunk) in self.chunks.iter_mut() {
+            if !chunk.evacuating {
+                continue;
+            }
+            chunk.evacuating = false;
+            if chunk.free.len() == chunk.block_num {
+                empty.push(*base);
+            } else if !chunk.free.is_empty() {
+                self.partial.insert(*base);
+            }
+        }
+        for base in empty {
+            if self.chunks.len() > 1 {
+                self.chunks.remove(&base);
+                central.release_chunk(base as *mut u8);
             } else {
-                prev = free.clone();
+                self.partial.insert(base);
             }
         }
     }
 
-    pub fn insert(&mut self, ptr: *const u8, mut size: usize) {
-        size = (size & (!(CONFIG.align_bytes - 1)))
-            + ((size & (CONFIG.align_bytes - 1)) != 0) as usize * CONFIG.align_bytes;
-        for page in self.pages.iter_mut() {
-            if page.start_ptr as *const u8 <= ptr
-                && ptr < unsafe { page.start_ptr.offset(page.tot_size as isize) }
-            {
-                if CONFIG.enable_mm_debug {
-                    print!(
-                        "[free] find chunk: size: {}, tot_size: {}, used_size: {}, has_free_list: {}\n",
-                        size,
-                        page.tot_size,
-                        page.used_size,
-                        page.free_list.is_some()
-                    );
+    fn is_evacuating(&self, ptr: *const u8) -> bool {
+        self.chunks
+            .range(..=ptr as usize)
+            .next_back()
+            .is_some_and(|(_, chunk)| chunk.evacuating)
+    }
+}
+
+// shared by every thread using the manager, the thread caches fall back to it
+struct Central {
+    classes: Vec<Mutex<SizeClass>>,
+    // size class of every aligned size up to the largest class
+    class_indexes: Vec<usize>,
+    // freed large blocks by their kind and size in pages, reused before asking the node again
+    large: Mutex<HashMap<(AllocKind, usize), Vec<*mut u8>>>,
+    placement: Placement,
+    next_node: AtomicUsize,
+    // bytes taken from every node
+    node_usage: Vec<AtomicUsize>,
+    huge_pages: HugePages,
+    regions: Vec<Mutex<Region>>,
+    // every region taken from the nodes, given back when the manager is dropped
+    registered: Mutex<Vec<MemoryRegion>>,
+    huge_page_bytes: AtomicUsize,
+    transparent_huge_page_bytes: AtomicUsize,
+    // released chunks, their pages come back when they are touched again
+    empty_chunks: Mutex<Vec<*mut u8>>,
+    released_bytes: AtomicUsize,
+    // bumped by every compaction, a thread cache seeing a new one gives back its blocks of
+    // evacuating chunks
+    flush_epoch: AtomicUsize,
+}
+
+// blocks are only handed out under the locks of their class
+unsafe impl Send for Central {}
+unsafe impl Sync for Central {}
+
+impl Central {
+    fn alloc_from_node(&self, size: usize, kind: AllocKind) -> *mut u8 {
+        let node = match self.placement {
+            Placement::Node(node) => node,
+            Placement::Interleave => {
+                (self.next_node.fetch_add(1, Ordering::Relaxed) % self.node_usage.len()) as i32
+            }
+            Placement::Split { table, kv } => match kind {
+                AllocKind::Table => table,
+                AllocKind::Kv => kv,
+            },
+        };
+        // a missing node falls back to the last one, without numa it is the only one
+        let node = node.clamp(0, self.node_usage.len() as i32 - 1);
+        let mut region = self.regions[node as usize].lock().unwrap();
+        let left = region.end as usize - region.next as usize;
+        if left < size {
+            let (ptr, region_size, backing) = if self.huge_pages == HugePages::None {
+                let region_size = size
+                    .next_multiple_of(CONFIG.page_size)
+                    .max(CONFIG.region_size);
+                let ptr = Numa::numa_alloc_onnode(region_size, node);
+                (ptr, region_size, HugePages::None)
+            } else {
+                let region_size = size
+                    .next_multiple_of(CONFIG.huge_page_size)
+                    .max(CONFIG.region_size);
+                let (ptr, backing) = Numa::alloc_huge_onnode(region_size, node, self.huge_pages);
+                (ptr, region_size, backing)
+            };
+            if ptr.is_null() {
+                return ptr;
+            }
+            self.registered
+                .lock()
+                .unwrap()
+                .push(Regions::register(ptr, region_size, node));
+            if CONFIG.enable_mm_debug {
+                println!("[alloc] alloc new region: {} {:?}", region_size, backing);
+            }
+            self.node_usage[node as usize].fetch_add(region_size, Ordering::Relaxed);
+            match backing {
+                HugePages::Explicit => {
+                    self.huge_page_bytes
+                        .fetch_add(region_size, Ordering::Relaxed);
                 }
-                if page.free_list.is_none() {
-                    if page.tot_size < size {
-                        panic!("[free] free error: tot_size < size");
-                    }
+                HugePages::Transparent => {
+                    self.transparent_huge_page_bytes
+                        .fetch_add(region_size, Ordering::Relaxed);
+                }
+                HugePages::None => {}
+            }
+            // a block filling most of a new region leaves the current one in use
+            if region_size - size < left {
+                return ptr;
+            }
+            region.next = ptr;
+            region.end = ptr.wrapping_add(region_size);
+        }
+        let ptr = region.next;
+        region.next = region.next.wrapping_add(size);
+        ptr
+    }
+
+    fn alloc_chunk(&self) -> *mut u8 {
+        if let Some(chunk) = self.empty_chunks.lock().unwrap().pop() {
+            self.released_bytes
+                .fetch_sub(CONFIG.slab_chunk_size, Ordering::Relaxed);
+            return chunk;
+        }
+        // chunks only hold kv blocks
+        self.alloc_from_node(CONFIG.slab_chunk_size, AllocKind::Kv)
+    }
 
-                    page.free_list = Some(Arc::new(Mutex::new(Free {
-                        ptr: ptr as *mut u8,
-                        size,
-                        next: None,
-                    })));
-                    page.used_size -= size;
-                    return;
+    fn release_chunk(&self, chunk: *mut u8) {
+        Numa::release(chunk, CONFIG.slab_chunk_size);
+        self.released_bytes
+            .fetch_add(CONFIG.slab_chunk_size, Ordering::Relaxed);
+        self.empty_chunks.lock().unwrap().push(chunk);
+    }
+
+    fn get_class_index(&self, size: usize) -> usize {
+        self.class_indexes[size.div_ceil(CONFIG.align_bytes)]
+    }
+
+    // moves up to num blocks of a class into cache
+    fn fill(&self, class: usize, cache: &mut Vec<*mut u8>, num: usize) {
+        let mut class = self.classes[class].lock().unwrap();
+        for _ in 0..num {
+            let ptr = class.alloc(self);
+            if ptr.is_null() {
+                break;
+            }
+            cache.push(ptr);
+        }
+    }
+
+    fn flush(&self, class: usize, cache: &mut Vec<*mut u8>, num: usize) {
+        let mut class = self.classes[class].lock().unwrap();
+        let start = cache.len() - num.min(cache.len());
+        for ptr in cache.drain(start..) {
+            class.free(ptr, self);
+        }
+    }
+
+    // gives back the cached blocks of chunks being evacuated, so that the chunks can empty
+    fn flush_evacuating(&self, cache: &mut ThreadCache) {
+        for class in 0..cache.classes.len() {
+            if cache.classes[class].is_empty() {
+                continue;
+            }
+            let mut size_class = self.classes[class].lock().unwrap();
+            cache.classes[class].retain(|&ptr| {
+                if !size_class.is_evacuating(ptr) {
+                    return true;
                 }
+                size_class.free(ptr, self);
+                false
+            });
+        }
+    }
+}
 
-                let mut free_list = page.free_list.clone();
-                let mut free = free_list.unwrap().clone();
-                let mut prev = free.clone();
-                let mut is_head = true;
-                while (free.lock().unwrap().ptr as *const u8) < ptr {
-                    is_head = false;
-                    prev = free.clone();
-                    if free.lock().unwrap().next.is_none() {
-                        break;
+impl Drop for Central {
+    fn drop(&mut self) {
+        for region in self.registered.lock().unwrap().drain(..) {
+            Regions::deregister(region.id);
+            Numa::numa_free(region.base, region.size);
+        }
+    }
+}
+
+// holds no reference, so that dropping the manager gives its regions back at once
+struct ThreadCache {
+    central: Weak<Central>,
+    classes: Vec<Vec<*mut u8>>,
+    // the flush epoch of the manager the cache has caught up with
+    epoch: usize,
+}
+
+impl Drop for ThreadCache {
+    fn drop(&mut self) {
+        let central = match self.central.upgrade() {
+            Some(central) => central,
+            None => return,
+        };
+        for class in 0..self.classes.len() {
+            let num = self.classes[class].len();
+            central.flush(class, &mut self.classes[class], num);
+        }
+    }
+}
+
+thread_local! {
+    // a cache for every manager used by the thread, most threads use one or two
+    static THREAD_CACHES: RefCell<Vec<ThreadCache>> = const { RefCell::new(Vec::new()) };
+}
+
+// small blocks such as kv blocks come from size classes, cached by every thread so that most
+// allocations take no lock; subtables and directories take whole pages
+pub struct MemoryManager {
+    central: Arc<Central>,
+}
+
+impl MemoryManager {
+    pub fn new() -> MemoryManager {
+        MemoryManager::with_placement(Placement::Node(CONFIG.default_numa_node))
+    }
+
+    pub fn with_placement(placement: Placement) -> MemoryManager {
+        MemoryManager::with_options(placement, HugePages::None)
+    }
+
+    // large blocks and chunks are carved from registered regions, of whole huge pages with them
+    pub fn with_options(placement: Placement, huge_pages: HugePages) -> MemoryManager {
+        let mut sizes = Vec::new();
+        let mut size = CONFIG.slab_min_size;
+        while size <= CONFIG.slab_max_size {
+            sizes.push(size);
+            // four classes between two powers of two, a block wastes at most a quarter
+            let step = (1 << (usize::BITS - 1 - size.leading_zeros())) / 4;
+            size += step.max(CONFIG.slab_min_size);
+        }
+        if sizes.last() != Some(&CONFIG.slab_max_size) {
+            sizes.push(CONFIG.slab_max_size);
+        }
+        let mut class_indexes = Vec::new();
+        let mut class = 0;
+        for aligned in 0..=CONFIG.slab_max_size / CONFIG.align_bytes {
+            while sizes[class] < aligned * CONFIG.align_bytes {
+                class += 1;
+            }
+            class_indexes.push(class);
+        }
+        MemoryManager {
+            central: Arc::new(Central {
+                classes: sizes
+                    .into_iter()
+                    .map(|size| {
+                        Mutex::new(SizeClass {
+                            size,
+                            chunks: BTreeMap::new(),
+                            partial: BTreeSet::new(),
+                        })
+                    })
+                    .collect(),
+                class_indexes,
+                large: Mutex::new(HashMap::new()),
+                placement,
+                next_node: AtomicUsize::new(0),
+                node_usage: (0..Numa::get_node_num())
+                    .map(|_| AtomicUsize::new(0))
+                    .collect(),
+                huge_pages,
+                regions: (0..Numa::get_node_num())
+                    .map(|_| {
+                        Mutex::new(Region {
+                            next: null_mut(),
+                            end: null_mut(),
+                        })
+                    })
+                    .collect(),
+                registered: Mutex::new(Vec::new()),
+                huge_page_bytes: AtomicUsize::new(0),
+                transparent_huge_page_bytes: AtomicUsize::new(0),
+                empty_chunks: Mutex::new(Vec::new()),
+                released_bytes: AtomicUsize::new(0),
+                flush_epoch: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    fn with_cache<R>(&self, f: impl FnOnce(&mut ThreadCache) -> R) -> Option<R> {
+        THREAD_CACHES
+            .try_with(|caches| {
+                let central = Arc::downgrade(&self.central);
+                let mut caches = caches.borrow_mut();
+                // the blocks cached for a dropped manager are gone with its regions
+                caches.retain(|cache| cache.central.strong_count() > 0);
+                let index = match caches
+                    .iter()
+                    .position(|cache| Weak::ptr_eq(&cache.central, &central))
+                {
+                    Some(index) => index,
+                    None => {
+                        caches.push(ThreadCache {
+                            central,
+                            classes: vec![Vec::new(); self.central.classes.len()],
+                            epoch: self.central.flush_epoch.load(Ordering::Acquire),
+                        });
+                        caches.len() - 1
                     }
-                    let tmp = free.clone();
-                    free = tmp.lock().unwrap().next.clone().unwrap();
+                };
+                let cache = &mut caches[index];
+                // a compaction has begun since the cache was last used
+                let epoch = self.central.flush_epoch.load(Ordering::Acquire);
+                if cache.epoch != epoch {
+                    self.central.flush_evacuating(cache);
+                    cache.epoch = epoch;
                 }
+                f(cache)
+            })
+            .ok()
+    }
 
-                if CONFIG.enable_mm_debug {
-                    print!(
-                        "[free] get next free: {}, is_head: {}\n",
-                        free.lock().unwrap().size,
-                        is_head
-                    );
-                }
+    fn get_page_num(size: usize) -> usize {
+        size.div_ceil(CONFIG.page_size)
+    }
 
-                let mut ptr_bound = ptr;
-                if !is_head && !prev.lock().unwrap().next.is_some() {
-                    ptr_bound = unsafe { page.start_ptr.offset(page.tot_size as isize) };
-                } else {
-                    ptr_bound = free.lock().unwrap().ptr;
-                }
+    fn malloc_large(&self, size: usize, kind: AllocKind) -> *mut u8 {
+        let page_num = MemoryManager::get_page_num(size);
+        if let Some(ptr) = self
+            .central
+            .large
+            .lock()
+            .unwrap()
+            .get_mut(&(kind, page_num))
+            .and_then(|blocks| blocks.pop())
+        {
+            self.central
+                .released_bytes
+                .fetch_sub(page_num * CONFIG.page_size, Ordering::Relaxed);
+            return ptr;
+        }
+        if CONFIG.enable_mm_debug {
+            println!("[alloc] alloc new page: {}", page_num);
+        }
+        self.central
+            .alloc_from_node(page_num * CONFIG.page_size, kind)
+    }
 
-                if ptr_bound < unsafe { ptr.offset(size as isize) } {
-                    panic!("[free] free error: ptr + size > ptr_bound");
-                }
+    fn free_large(&self, ptr: *mut u8, size: usize, kind: AllocKind) {
+        let page_num = MemoryManager::get_page_num(size);
+        Numa::release(ptr, page_num * CONFIG.page_size);
+        self.central
+            .released_bytes
+            .fetch_add(page_num * CONFIG.page_size, Ordering::Relaxed);
+        self.central
+            .large
+            .lock()
+            .unwrap()
+            .entry((kind, page_num))
+            .or_default()
+            .push(ptr);
+    }
 
-                // insert new free
-                if is_head {
-                    let new_next = free.clone();
-                    let new_free = Arc::new(Mutex::new(Free {
-                        ptr: ptr as *mut u8,
-                        size,
-                        next: Some(new_next),
-                    }));
-                    page.free_list = Some(new_free.clone());
-                } else {
-                    let new_next = prev.lock().unwrap().next.clone();
-                    let new_free = Arc::new(Mutex::new(Free {
-                        ptr: ptr as *mut u8,
-                        size,
-                        next: new_next,
-                    }));
-                    prev.lock().unwrap().next = Some(new_free.clone());
-                }
-                page.used_size -= size;
-                // merge
-                MemoryManager::merge(page);
-                return;
+    // null if the node is out of memory
+    pub fn malloc(&self, size: usize) -> *mut u8 {
+        if size > CONFIG.slab_max_size {
+            return self.malloc_large(size, AllocKind::Kv);
+        }
+        let class = self.central.get_class_index(size);
+        let cached = self.with_cache(|cache| {
+            let blocks = &mut cache.classes[class];
+            if blocks.is_empty() {
+                self.central.fill(class, blocks, CONFIG.slab_cache_num / 2);
             }
+            blocks.pop()
+        });
+        match cached {
+            Some(ptr) => ptr.unwrap_or(null_mut()),
+            // the thread is exiting, its cache is gone
+            None => self.central.classes[class]
+                .lock()
+                .unwrap()
+                .alloc(&self.central),
         }
     }
 
-    pub fn malloc(&mut self, size: usize) -> *mut u8 {
-        let mut ptr = self.find_from_alloced_pages(size);
-        if CONFIG.enable_mm_debug {
-            print!("[malloc] malloc: {} find: {}\n", size, ptr.is_some());
+    // size must be the one given to malloc
+    pub fn free(&self, ptr: *const u8, size: usize) {
+        let ptr = ptr as *mut u8;
+        if size > CONFIG.slab_max_size {
+            self.free_large(ptr, size, AllocKind::Kv);
+            return;
+        }
+        let class = self.central.get_class_index(size);
+        let cached = self.with_cache(|cache| {
+            let blocks = &mut cache.classes[class];
+            blocks.push(ptr);
+            if blocks.len() > CONFIG.slab_cache_num {
+                self.central.flush(class, blocks, CONFIG.slab_cache_num / 2);
+            }
+        });
+        if cached.is_none() {
+            self.central.classes[class]
+                .lock()
+                .unwrap()
+                .free(ptr, &self.central);
         }
-        if ptr.is_none() {
-            self.alloc_new_page(size / CONFIG.page_size + (size % CONFIG.page_size > 0) as usize);
-            ptr = self.find_from_alloced_pages(size);
+    }
+
+    // blocks of the given sizes, those of one size class taken at once and laid out one after
+    // another in fresh chunks; null where the node is out of memory, every block is freed on its
+    // own
+    pub fn malloc_batch(&self, sizes: &[usize]) -> Vec<*mut u8> {
+        let mut blocks = vec![null_mut(); sizes.len()];
+        let mut classes: HashMap<usize, Vec<usize>> = HashMap::new();
+        for (index, &size) in sizes.iter().enumerate() {
+            if size > CONFIG.slab_max_size {
+                blocks[index] = self.malloc_large(size, AllocKind::Kv);
+            } else {
+                classes
+                    .entry(self.central.get_class_index(size))
+                    .or_default()
+                    .push(index);
+            }
         }
-        ptr.unwrap()
+        for (class, indexes) in classes {
+            let fresh = self.central.classes[class]
+                .lock()
+                .unwrap()
+                .alloc_fresh(&self.central, indexes.len());
+            for (index, ptr) in indexes.into_iter().zip(fresh) {
+                blocks[index] = ptr;
+            }
+        }
+        blocks
+    }
+
+    // a block of a subtable or a directory, it never shares a chunk with kv blocks
+    pub fn malloc_table(&self, size: usize) -> *mut u8 {
+        self.malloc_large(size, AllocKind::Table)
     }
 
-    pub fn free(&mut self, ptr: *const u8, size: usize) {
-        self.insert(ptr, size);
+    // size must be the one given to malloc_table
+    pub fn free_table(&self, ptr: *const u8, size: usize) {
+        self.free_large(ptr as *mut u8, size, AllocKind::Table);
+    }
+
+    // stops handing out blocks of chunks with less than max_used_percent of them in use, so that
+    // a compaction can move their blocks away, return the number of such chunks; every thread
+    // cache gives back its blocks of them on its next malloc or free, those of a thread that
+    // does neither until end_compaction keep their chunks from being released
+    pub fn begin_compaction(&self, max_used_percent: usize) -> usize {
+        // blocks cached by this thread are not counted as in use
+        self.with_cache(|cache| {
+            for class in 0..cache.classes.len() {
+                let num = cache.classes[class].len();
+                self.central.flush(class, &mut cache.classes[class], num);
+            }
+        });
+        let evacuated_num = self
+            .central
+            .classes
+            .iter()
+            .map(|class| class.lock().unwrap().evacuate(max_used_percent))
+            .sum();
+        self.central.flush_epoch.fetch_add(1, Ordering::Release);
+        evacuated_num
+    }
+
+    // chunks emptied by the compaction are released
+    pub fn end_compaction(&self) {
+        for class in self.central.classes.iter() {
+            class.lock().unwrap().finish_evacuation(&self.central);
+        }
+    }
+
+    pub fn is_evacuating(&self, ptr: *const u8, size: usize) -> bool {
+        if size > CONFIG.slab_max_size {
+            return false;
+        }
+        self.central.classes[self.central.get_class_index(size)]
+            .lock()
+            .unwrap()
+            .is_evacuating(ptr)
+    }
+
+    pub fn get_placement(&self) -> Placement {
+        self.central.placement
+    }
+
+    // bytes taken from every node, released pages included
+    pub fn get_node_usage(&self) -> Vec<usize> {
+        self.central
+            .node_usage
+            .iter()
+            .map(|usage| usage.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    pub fn get_stats(&self) -> MemoryStats {
+        MemoryStats {
+            node_bytes: self.get_node_usage(),
+            huge_page_bytes: self.central.huge_page_bytes.load(Ordering::Relaxed),
+            transparent_huge_page_bytes: self
+                .central
+                .transparent_huge_page_bytes
+                .load(Ordering::Relaxed),
+            released_bytes: self.central.released_bytes.load(Ordering::Relaxed),
+        }
     }
 }
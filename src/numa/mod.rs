@@ -1,2 +1,3 @@
 pub mod numa;
-pub mod mm;
\ No newline at end of file
+pub mod mm;
+pub mod region;
\ No newline at end of file
@@ -1,30 +1,131 @@
+use crate::cfg::config::CONFIG;
+use std::sync::OnceLock;
+
+#[cfg(feature = "numa")]
 #[link(name = "numa")]
 extern "C" {
     pub fn numa_available() -> i32;
+    pub fn numa_num_configured_nodes() -> i32;
     pub fn numa_alloc_onnode(size: usize, node: i32) -> *mut u8;
     pub fn numa_free(ptr: *mut u8, size: usize);
+    pub fn numa_tonode_memory(start: *mut u8, size: usize, node: i32);
+}
+
+extern "C" {
+    fn mmap(addr: *mut u8, length: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut u8;
+    fn munmap(addr: *mut u8, length: usize) -> i32;
+    fn madvise(addr: *mut u8, length: usize, advice: i32) -> i32;
+}
+
+const PROT_READ_WRITE: i32 = 0x1 | 0x2;
+const MAP_PRIVATE_ANONYMOUS: i32 = 0x02 | 0x20;
+const MAP_HUGETLB: i32 = 0x40000;
+const MADV_DONTNEED: i32 = 4;
+const MADV_HUGEPAGE: i32 = 14;
+
+// how regions are backed, each falls back to the next one when the kernel has none
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HugePages {
+    // reserved huge pages of the kernel, see /proc/sys/vm/nr_hugepages
+    Explicit,
+    // huge pages advised with madvise, the kernel backs them when it can
+    Transparent,
+    None,
+}
+
+fn map(size: usize, flags: i32) -> *mut u8 {
+    let ptr = unsafe { mmap(std::ptr::null_mut(), size, PROT_READ_WRITE, flags, -1, 0) };
+    // MAP_FAILED
+    if ptr as isize == -1 {
+        return std::ptr::null_mut();
+    }
+    ptr
 }
 
 pub struct Numa {}
 
 impl Numa {
+    // -1 without numa support in the kernel or without libnuma, memory then comes from mmap
     pub fn numa_available() -> i32 {
-        unsafe {
-            numa_available()
+        static AVAILABLE: OnceLock<i32> = OnceLock::new();
+        #[cfg(feature = "numa")]
+        return *AVAILABLE.get_or_init(|| unsafe { numa_available() });
+        #[cfg(not(feature = "numa"))]
+        return *AVAILABLE.get_or_init(|| -1);
+    }
+
+    // a machine without numa is one node
+    pub fn get_node_num() -> usize {
+        #[cfg(feature = "numa")]
+        if Numa::numa_available() != -1 {
+            return unsafe { numa_num_configured_nodes() }.max(1) as usize;
         }
+        1
     }
 
+    // zeroed memory, null if none is left
     pub fn numa_alloc_onnode(size: usize, node: i32) -> *mut u8 {
-        unsafe {
-            numa_alloc_onnode(size, node)
+        #[cfg(feature = "numa")]
+        if Numa::numa_available() != -1 {
+            return unsafe { numa_alloc_onnode(size, node) };
         }
+        map(size, MAP_PRIVATE_ANONYMOUS)
     }
 
-    pub fn numa_free(ptr: *mut u8, size: usize) {
-        unsafe {
-            numa_free(ptr, size)
+    // zeroed memory of a multiple of huge_page_size, aligned to it, and how it is backed
+    pub fn alloc_huge_onnode(
+        size: usize,
+        node: i32,
+        huge_pages: HugePages,
+    ) -> (*mut u8, HugePages) {
+        let mut ptr = std::ptr::null_mut();
+        let mut backing = huge_pages;
+        if backing == HugePages::Explicit {
+            ptr = map(size, MAP_PRIVATE_ANONYMOUS | MAP_HUGETLB);
+            if ptr.is_null() {
+                backing = HugePages::Transparent;
+            }
+        }
+        if backing != HugePages::Explicit {
+            // map one huge page more and trim both ends, so that the region is aligned
+            let page = CONFIG.huge_page_size;
+            let mapped = map(size + page, MAP_PRIVATE_ANONYMOUS);
+            if mapped.is_null() {
+                return (mapped, HugePages::None);
+            }
+            let head = (page - mapped as usize % page) % page;
+            ptr = mapped.wrapping_add(head);
+            unsafe {
+                if head > 0 {
+                    munmap(mapped, head);
+                }
+                munmap(ptr.wrapping_add(size), page - head);
+            }
+            if backing == HugePages::Transparent
+                && unsafe { madvise(ptr, size, MADV_HUGEPAGE) } != 0
+            {
+                backing = HugePages::None;
+            }
         }
+        // pages are placed when first touched, so the policy is set before
+        #[cfg(feature = "numa")]
+        if Numa::numa_available() != -1 {
+            unsafe { numa_tonode_memory(ptr, size, node) };
+        }
+        (ptr, backing)
+    }
+
+    // gives the pages back to the os, they read as zero when touched again
+    pub fn release(ptr: *mut u8, size: usize) {
+        unsafe { madvise(ptr, size, MADV_DONTNEED) };
     }
 
-    
-}
\ No newline at end of file
+    pub fn numa_free(ptr: *mut u8, size: usize) {
+        #[cfg(feature = "numa")]
+        if Numa::numa_available() != -1 {
+            unsafe { numa_free(ptr, size) };
+            return;
+        }
+        unsafe { munmap(ptr, size) };
+    }
+}
@@ -0,0 +1,203 @@
+use crate::cfg::config::CONFIG;
+use std::collections::BTreeMap;
+use std::sync::atomic::{fence, AtomicUsize, Ordering};
+use std::sync::RwLock;
+
+const REGION_ID_BITS: usize = 16;
+const REGION_OFFSET_BITS: usize = 32;
+const REGION_NUM: usize = 1 << REGION_ID_BITS;
+
+// memory registered for remote access, addresses stored in slots and directory entries are a
+// region id and an offset into it, so that they do not depend on where the region is mapped
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryRegion {
+    // 0 is never used, a zero address stays null
+    pub id: u16,
+    // a client must present it to access the region, an id registered again gets a new key
+    pub rkey: u32,
+    pub base: *mut u8,
+    pub size: usize,
+    pub node: i32,
+}
+
+unsafe impl Send for MemoryRegion {}
+unsafe impl Sync for MemoryRegion {}
+
+struct RegionTable {
+    regions: Vec<Option<MemoryRegion>>,
+    // id of the region starting at every base
+    bases: BTreeMap<usize, u16>,
+    free_ids: Vec<u16>,
+    next_rkey: u32,
+}
+
+static TABLE: RwLock<RegionTable> = RwLock::new(RegionTable {
+    regions: Vec::new(),
+    bases: BTreeMap::new(),
+    free_ids: Vec::new(),
+    next_rkey: 1,
+});
+
+// base and size of every region by id, read without the lock on every access to a slot or an
+// entry
+static BASES: [AtomicUsize; REGION_NUM] = [const { AtomicUsize::new(0) }; REGION_NUM];
+static SIZES: [AtomicUsize; REGION_NUM] = [const { AtomicUsize::new(0) }; REGION_NUM];
+
+// the bases in order with their ids, rewritten under the table lock; the version is odd while
+// they are rewritten, a reader takes no lock and searches them again if it has changed
+static SORTED_VERSION: AtomicUsize = AtomicUsize::new(0);
+static SORTED_NUM: AtomicUsize = AtomicUsize::new(0);
+static SORTED_BASES: [AtomicUsize; REGION_NUM] = [const { AtomicUsize::new(0) }; REGION_NUM];
+static SORTED_IDS: [AtomicUsize; REGION_NUM] = [const { AtomicUsize::new(0) }; REGION_NUM];
+
+fn publish_sorted(table: &RegionTable) {
+    let version = SORTED_VERSION.load(Ordering::Relaxed);
+    SORTED_VERSION.store(version + 1, Ordering::Relaxed);
+    fence(Ordering::Release);
+    for (index, (base, id)) in table.bases.iter().enumerate() {
+        SORTED_BASES[index].store(*base, Ordering::Relaxed);
+        SORTED_IDS[index].store(*id as usize, Ordering::Relaxed);
+    }
+    SORTED_NUM.store(table.bases.len(), Ordering::Relaxed);
+    SORTED_VERSION.store(version + 2, Ordering::Release);
+}
+
+// the id of the last region starting at or below the pointer
+fn find_sorted(pointer: usize) -> Option<u16> {
+    loop {
+        let version = SORTED_VERSION.load(Ordering::Acquire);
+        if version.is_multiple_of(2) {
+            let (mut low, mut high) = (0, SORTED_NUM.load(Ordering::Relaxed).min(REGION_NUM));
+            while low < high {
+                let middle = (low + high) / 2;
+                if SORTED_BASES[middle].load(Ordering::Relaxed) <= pointer {
+                    low = middle + 1;
+                } else {
+                    high = middle;
+                }
+            }
+            let id = match low {
+                0 => None,
+                _ => Some(SORTED_IDS[low - 1].load(Ordering::Relaxed) as u16),
+            };
+            fence(Ordering::Acquire);
+            if SORTED_VERSION.load(Ordering::Relaxed) == version {
+                return id;
+            }
+        }
+        std::hint::spin_loop();
+    }
+}
+
+pub struct Regions {}
+
+impl Regions {
+    pub fn register(base: *mut u8, size: usize, node: i32) -> MemoryRegion {
+        assert!(
+            size <= 1 << REGION_OFFSET_BITS,
+            "region of {} bytes is too big",
+            size
+        );
+        let mut table = TABLE.write().unwrap();
+        if table.regions.is_empty() {
+            table.regions.push(None);
+        }
+        let id = match table.free_ids.pop() {
+            Some(id) => id,
+            None => {
+                assert!(table.regions.len() < REGION_NUM, "no region id is left");
+                table.regions.push(None);
+                (table.regions.len() - 1) as u16
+            }
+        };
+        let region = MemoryRegion {
+            id,
+            rkey: table.next_rkey,
+            base,
+            size,
+            node,
+        };
+        table.next_rkey = table.next_rkey.wrapping_add(1).max(1);
+        table.regions[id as usize] = Some(region);
+        table.bases.insert(base as usize, id);
+        SIZES[id as usize].store(size, Ordering::Release);
+        BASES[id as usize].store(base as usize, Ordering::Release);
+        publish_sorted(&table);
+        if CONFIG.enable_mm_debug {
+            println!("[region] register {:?}", region);
+        }
+        region
+    }
+
+    // addresses into the region are dangling afterwards
+    pub fn deregister(id: u16) {
+        let mut table = TABLE.write().unwrap();
+        if let Some(region) = table.regions[id as usize].take() {
+            table.bases.remove(&(region.base as usize));
+            publish_sorted(&table);
+            BASES[id as usize].store(0, Ordering::Release);
+            SIZES[id as usize].store(0, Ordering::Release);
+            table.free_ids.push(id);
+        }
+    }
+
+    pub fn get(id: u16) -> Option<MemoryRegion> {
+        TABLE
+            .read()
+            .unwrap()
+            .regions
+            .get(id as usize)
+            .copied()
+            .flatten()
+    }
+
+    // what a remote client is told about the memory it may access
+    pub fn get_regions() -> Vec<MemoryRegion> {
+        TABLE
+            .read()
+            .unwrap()
+            .regions
+            .iter()
+            .flatten()
+            .copied()
+            .collect()
+    }
+
+    // a local pointer as the 48 bits of region id and offset, null stays 0; it takes no lock,
+    // it runs on every write of a slot or an entry
+    pub fn to_remote(pointer: u64) -> u64 {
+        if pointer == 0 {
+            return 0;
+        }
+        let id = match find_sorted(pointer as usize) {
+            Some(id) => id,
+            None => panic!("pointer {:#x} is not in a registered region", pointer),
+        };
+        let offset = pointer.wrapping_sub(BASES[id as usize].load(Ordering::Acquire) as u64);
+        assert!(
+            offset < SIZES[id as usize].load(Ordering::Acquire) as u64,
+            "pointer {:#x} is not in a registered region",
+            pointer
+        );
+        ((id as u64) << REGION_OFFSET_BITS) | offset
+    }
+
+    // the local pointer of the 48 bits of region id and offset
+    pub fn to_local(address: u64) -> u64 {
+        if address == 0 {
+            return 0;
+        }
+        let id = (address >> REGION_OFFSET_BITS) as usize & (REGION_NUM - 1);
+        let offset = address & ((1 << REGION_OFFSET_BITS) - 1);
+        BASES[id].load(Ordering::Acquire) as u64 + offset
+    }
+
+    // the access of a remote client, none for a wrong key or out of the region
+    pub fn translate(id: u16, rkey: u32, offset: u64, length: usize) -> Option<*mut u8> {
+        let region = Regions::get(id)?;
+        if region.rkey != rkey || offset + length as u64 > region.size as u64 {
+            return None;
+        }
+        Some(region.base.wrapping_add(offset as usize))
+    }
+}
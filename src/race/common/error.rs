@@ -0,0 +1,38 @@
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RaceError {
+    // an operation kept failing until the retry policy gave up
+    RetryLimitExceeded,
+    // the deadline given by the caller has passed
+    DeadlineExceeded,
+    // the caller has cancelled the operation
+    Cancelled,
+    // the memory pool has failed, the caller may fail over to a backup
+    PoolUnavailable,
+    // no memory is left for a new kv block
+    AllocationFailed,
+    // the kv blocks of the pool reach its quota, and nothing could be evicted
+    QuotaExceeded,
+    // an item being moved by a split or a merge finds all of its buckets full
+    NoFreeSlot,
+    // the client has been crashed by fault injection, it holds its locks until recovered
+    Crashed,
+}
+
+impl fmt::Display for RaceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RaceError::RetryLimitExceeded => write!(f, "retry limit exceeded"),
+            RaceError::DeadlineExceeded => write!(f, "deadline exceeded"),
+            RaceError::Cancelled => write!(f, "operation cancelled"),
+            RaceError::PoolUnavailable => write!(f, "memory pool unavailable"),
+            RaceError::AllocationFailed => write!(f, "kv block allocation failed"),
+            RaceError::QuotaExceeded => write!(f, "memory quota exceeded"),
+            RaceError::NoFreeSlot => write!(f, "no free slot for a moved item"),
+            RaceError::Crashed => write!(f, "client crashed"),
+        }
+    }
+}
+
+impl std::error::Error for RaceError {}
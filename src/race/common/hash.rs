@@ -49,7 +49,9 @@ impl Hash {
         match method {
             HashMethod::CombinedBucket1 => Hash::hash_1(key, CONFIG.bucket_group_num),
             HashMethod::CombinedBucket2 => Hash::hash_2(key, CONFIG.bucket_group_num),
-            HashMethod::FingerPrint => Hash::hash_3(key, CONFIG.bits_of_byte * CONFIG.fp_size),
+            HashMethod::FingerPrint => {
+                Hash::hash_3(key, 1 << (CONFIG.bits_of_byte * CONFIG.fp_size))
+            }
             HashMethod::Directory => Hash::hash_4(key, CONFIG.max_entry_num),
             _ => panic!("Invalid hash method!"),
         }
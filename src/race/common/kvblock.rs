@@ -2,8 +2,9 @@ use crate::cfg::config::CONFIG;
 use crate::numa::mm::memcpy;
 use crate::numa::mm::MemoryManager;
 use crc::{Crc, CRC_64_REDIS};
-use std::mem::size_of;
-use std::sync::{Arc, Mutex};
+use std::mem::{align_of, size_of};
+use std::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+use std::sync::Arc;
 
 pub struct KVBlock {
     pub klen: u16,
@@ -13,31 +14,66 @@ pub struct KVBlock {
     pub crc64: u64,
 }
 
+// its table is built once instead of for every block
+static CRC: Crc<u64> = Crc::<u64>::new(&CRC_64_REDIS);
+
+// flags of a block, not covered by the checksum
+// set by searches and cleared by the clock hand of eviction
+const REFERENCED: u8 = 1;
+// written while the pool tracks accesses, an access record follows the key and the value
+const TRACKED: u8 = 2;
+
 pub struct KVBlockMem {
     klen: u16,
     vlen: u16,
+    flags: AtomicU8,
     crc64: u64,
 }
 
+// tick of the pool access clock at the last sampled search and the number of them
+struct AccessRecord {
+    last_access: AtomicU32,
+    access_num: AtomicU32,
+}
+
 impl KVBlockMem {
+    // null if no memory is left
     pub fn new(
         key: &String,
         value: &String,
-        memory_manager: Arc<Mutex<MemoryManager>>,
+        tracked: bool,
+        memory_manager: Arc<MemoryManager>,
     ) -> *const Self {
-        let kvblock_pointer = memory_manager
-            .lock()
-            .unwrap()
-            .malloc(size_of::<KVBlockMem>() + key.len() + value.len());
+        let kvblock_pointer = memory_manager.malloc(KVBlockMem::get_length(key, value, tracked));
         if kvblock_pointer == std::ptr::null_mut() {
-            panic!("kvblock malloc failed");
+            return std::ptr::null();
         }
+        KVBlockMem::new_at(kvblock_pointer, key, value, tracked)
+    }
+
+    pub fn new_at(
+        kvblock_pointer: *mut u8,
+        key: &String,
+        value: &String,
+        tracked: bool,
+    ) -> *const Self {
         unsafe {
             (*(kvblock_pointer as *mut Self)).klen = key.len() as u16;
             (*(kvblock_pointer as *mut Self)).vlen = value.len() as u16;
+            (*(kvblock_pointer as *mut Self)).flags =
+                AtomicU8::new(if tracked { TRACKED } else { 0 });
+            if tracked {
+                let record = kvblock_pointer
+                    .wrapping_add(KVBlockMem::get_record_offset(key.len(), value.len()))
+                    as *mut AccessRecord;
+                record.write(AccessRecord {
+                    last_access: AtomicU32::new(0),
+                    access_num: AtomicU32::new(0),
+                });
+            }
         }
         let combined_string = key.to_owned() + value.to_owned().as_str();
-        let checksum = Crc::<u64>::new(&CRC_64_REDIS).checksum(combined_string.as_bytes());
+        let checksum = CRC.checksum(combined_string.as_bytes());
         unsafe {
             (*(kvblock_pointer as *mut Self)).crc64 = checksum;
         }
@@ -48,6 +84,27 @@ impl KVBlockMem {
         kvblock_pointer as *const Self
     }
 
+    // a block of a pool tracking accesses is longer by its access record
+    pub fn get_length(key: &str, value: &str, tracked: bool) -> usize {
+        if tracked {
+            KVBlockMem::get_record_offset(key.len(), value.len()) + size_of::<AccessRecord>()
+        } else {
+            size_of::<KVBlockMem>() + key.len() + value.len()
+        }
+    }
+
+    fn get_record_offset(klen: usize, vlen: usize) -> usize {
+        (size_of::<KVBlockMem>() + klen + vlen).next_multiple_of(align_of::<AccessRecord>())
+    }
+
+    fn get_record(&self) -> Option<&AccessRecord> {
+        if self.flags.load(Ordering::Relaxed) & TRACKED == 0 {
+            return None;
+        }
+        let offset = KVBlockMem::get_record_offset(self.klen as usize, self.vlen as usize);
+        Some(unsafe { &*((self as *const Self as *const u8).add(offset) as *const AccessRecord) })
+    }
+
     pub fn get(&self) -> KVBlock {
         let kl = self.klen;
         let vl = self.vlen;
@@ -80,6 +137,70 @@ impl KVBlockMem {
     }
 
     pub fn get_total_length(&self) -> usize {
-        size_of::<KVBlockMem>() + self.klen as usize + self.vlen as usize
+        let klen = self.klen as usize;
+        let vlen = self.vlen as usize;
+        if self.flags.load(Ordering::Relaxed) & TRACKED != 0 {
+            KVBlockMem::get_record_offset(klen, vlen) + size_of::<AccessRecord>()
+        } else {
+            size_of::<KVBlockMem>() + klen + vlen
+        }
+    }
+
+    pub fn set_referenced(&self) {
+        // a plain load first, so that hot blocks are not written on every search
+        if self.flags.load(Ordering::Relaxed) & REFERENCED == 0 {
+            self.flags.fetch_or(REFERENCED, Ordering::Relaxed);
+        }
+    }
+
+    // true if the block was referenced since the last call
+    pub fn clear_referenced(&self) -> bool {
+        self.flags.fetch_and(!REFERENCED, Ordering::Relaxed) & REFERENCED != 0
+    }
+
+    // a block written before the pool tracked accesses keeps none
+    pub fn record_access(&self, tick: u32) {
+        if let Some(record) = self.get_record() {
+            record.last_access.store(tick, Ordering::Relaxed);
+            record.access_num.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // 0 for a block without an access record, it is taken as never accessed
+    pub fn get_last_access(&self) -> u32 {
+        self.get_record()
+            .map_or(0, |record| record.last_access.load(Ordering::Relaxed))
+    }
+
+    pub fn get_access_num(&self) -> u32 {
+        self.get_record()
+            .map_or(0, |record| record.access_num.load(Ordering::Relaxed))
+    }
+
+    pub fn set_last_access(&self, tick: u32) {
+        if let Some(record) = self.get_record() {
+            record.last_access.store(tick, Ordering::Relaxed);
+        }
+    }
+
+    // a copy of the item keeps its hotness, accesses to the old block in the meantime are lost
+    pub fn copy_access(&self, from: &KVBlockMem) {
+        if from.flags.load(Ordering::Relaxed) & REFERENCED != 0 {
+            self.set_referenced();
+        }
+        if let Some(record) = self.get_record() {
+            record
+                .last_access
+                .store(from.get_last_access(), Ordering::Relaxed);
+            record
+                .access_num
+                .store(from.get_access_num(), Ordering::Relaxed);
+        }
+    }
+
+    // only for fault injection, the checksum never matches the content again
+    #[cfg(any(test, feature = "fault-injection"))]
+    pub fn corrupt(&mut self) {
+        self.crc64 = !self.crc64;
     }
 }
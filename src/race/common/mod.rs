@@ -1,3 +1,6 @@
 pub mod hash;
 pub mod kvblock;
-pub mod utils;
\ No newline at end of file
+pub mod utils;
+pub mod error;
+pub mod retry;
+pub mod options;
\ No newline at end of file
@@ -0,0 +1,83 @@
+use super::error::RaceError;
+use super::options::OpOptions;
+use crate::cfg::config::CONFIG;
+use std::time::{Duration, Instant};
+
+// retry budget of one operation, spinning first and then sleeping with exponential backoff
+pub struct RetryState {
+    retry_times: usize,
+    deadline: Instant,
+    seed: u64,
+    options: OpOptions,
+}
+
+impl RetryState {
+    pub fn new() -> Self {
+        let seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .subsec_nanos() as u64;
+        RetryState {
+            retry_times: 0,
+            deadline: Instant::now() + Duration::from_millis(CONFIG.retry_deadline_ms),
+            // xorshift never leaves zero
+            seed: seed | 1,
+            options: OpOptions::default(),
+        }
+    }
+
+    pub fn reset(&mut self, options: &OpOptions) {
+        self.retry_times = 0;
+        self.deadline = Instant::now() + Duration::from_millis(CONFIG.retry_deadline_ms);
+        self.options = options.clone();
+    }
+
+    // the caller's limits come first, so that it learns why the operation stopped
+    pub fn check(&self) -> Result<(), RaceError> {
+        if let Some(cancel) = &self.options.cancel {
+            if cancel.is_cancelled() {
+                return Err(RaceError::Cancelled);
+            }
+        }
+        if let Some(deadline) = self.options.deadline {
+            if Instant::now() >= deadline {
+                return Err(RaceError::DeadlineExceeded);
+            }
+        }
+        Ok(())
+    }
+
+    fn next_random(&mut self) -> u64 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        self.seed
+    }
+
+    pub fn backoff(&mut self) -> Result<(), RaceError> {
+        self.check()?;
+        self.retry_times += 1;
+        if self.retry_times > CONFIG.max_retry_times || Instant::now() >= self.deadline {
+            return Err(RaceError::RetryLimitExceeded);
+        }
+        if self.retry_times <= CONFIG.retry_spin_times {
+            std::thread::yield_now();
+            return Ok(());
+        }
+
+        // sleep a random time in the upper half of the backoff, so that clients do not retry together
+        let shift = std::cmp::min(self.retry_times - CONFIG.retry_spin_times, 32);
+        let backoff = std::cmp::min(
+            CONFIG.retry_base_backoff_us << shift,
+            CONFIG.retry_max_backoff_us,
+        );
+        let jitter = self.next_random() % (backoff / 2 + 1);
+        let mut sleep = Duration::from_micros(backoff - jitter);
+        if let Some(deadline) = self.options.deadline {
+            // do not sleep past the caller's deadline
+            sleep = std::cmp::min(sleep, deadline.saturating_duration_since(Instant::now()));
+        }
+        std::thread::sleep(sleep);
+        Ok(())
+    }
+}
@@ -1,4 +1,5 @@
 use crate::hash::{Hash, HashMethod};
+use crate::numa::region::Regions;
 use crate::race::mempool::subtable::{CombinedBucket, SlotPos};
 use crate::CONFIG;
 use crate::{Bucket, KVBlockMem};
@@ -39,6 +40,20 @@ impl RaceUtils {
             > 0
     }
 
+    pub fn get_lock_owner(data: u64) -> u8 {
+        (data
+            >> (CONFIG.bits_of_byte
+                * (size_of::<u64>() - size_of::<u8>() - CONFIG.directory_lock_offset)))
+            as u8
+    }
+
+    pub fn get_time_ms() -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+    }
+
     pub fn get_new_suffix_from_old(old_index: u64, old_local_depth: u8) -> u64 {
         RaceUtils::restrict_suffix_to(
             RaceUtils::add_bit_to_suffix(old_index as u64, old_local_depth + 1),
@@ -47,14 +62,15 @@ impl RaceUtils {
     }
 
     pub fn get_kv_pointer(data: u64) -> *const KVBlockMem {
-        (data
-            & !(0xFF
-                << CONFIG.bits_of_byte
-                    * (size_of::<u64>() - size_of::<u8>() - CONFIG.slot_fp_offset))
-            & !(0xFF
-                << CONFIG.bits_of_byte
-                    * (size_of::<u64>() - size_of::<u8>() - CONFIG.slot_len_offset)))
-            as *const KVBlockMem
+        Regions::to_local(
+            data
+                & !(0xFF
+                    << (CONFIG.bits_of_byte
+                        * (size_of::<u64>() - size_of::<u8>() - CONFIG.slot_fp_offset)))
+                & !(0xFF
+                    << (CONFIG.bits_of_byte
+                        * (size_of::<u64>() - size_of::<u8>() - CONFIG.slot_len_offset))),
+        ) as *const KVBlockMem
     }
 
     pub fn get_kvblock_from_pointer(kv_pointer: *const KVBlockMem) -> Option<KVBlock> {
@@ -73,10 +89,9 @@ impl RaceUtils {
 
     pub fn set_data(key: &String, val: &String, ptr: u64) -> u64 {
         let fp = Hash::hash(&key, HashMethod::FingerPrint) as u8;
-        let mut len = size_of::<KVBlockMem>() + key.len() + val.len();
-        if len > u8::MAX as usize {
-            assert!(true, "size of kv is too big");
-        }
+        // the length only tells a taken slot from an empty one, it stays clear of the inline tag
+        let len = (size_of::<KVBlockMem>() + key.len() + val.len())
+            .min(CONFIG.slot_inline_tag as usize - 1);
         let mut data = 0 as u64;
         data = (data
             & (0xFF
@@ -85,7 +100,7 @@ impl RaceUtils {
             & (0xFF
                 << CONFIG.bits_of_byte
                     * (size_of::<u64>() - size_of::<u8>() - CONFIG.slot_len_offset)))
-            | ptr;
+            | Regions::to_remote(ptr);
 
         data = (data
             & !(0xFF
@@ -105,6 +120,35 @@ impl RaceUtils {
         data
     }
 
+    // the key and the value fit into the bytes of a slot beside its length, a key is never
+    // inlined by a hash of it, see MemPool::set_inline_values
+    pub fn can_inline(key: &str, val: &str) -> bool {
+        key.len() + val.len() <= CONFIG.inline_data_size
+    }
+
+    // the byte of the slot word that holds the length, in little endian order
+    pub fn get_inline_len_index() -> usize {
+        size_of::<u64>() - size_of::<u8>() - CONFIG.slot_len_offset
+    }
+
+    // the key and then the value in the bytes of the slot around its length, which holds the
+    // inline tag and both lengths, so that the item is found by its whole key and moved by a
+    // split without a kv block
+    pub fn set_inline_data(key: &String, val: &String) -> u64 {
+        let len = CONFIG.slot_inline_tag
+            | ((key.len() as u8) << CONFIG.inline_len_bits)
+            | val.len() as u8;
+        let mut bytes: Vec<u8> = key.bytes().chain(val.bytes()).collect();
+        bytes.resize(CONFIG.inline_data_size, 0);
+        bytes.insert(RaceUtils::get_inline_len_index(), len);
+        u64::from_le_bytes(bytes.try_into().unwrap())
+    }
+
+    pub fn get_crc(key: &str, value: &str) -> u64 {
+        let combined_string = key.to_owned() + value;
+        Crc::<u64>::new(&CRC_64_REDIS).checksum(combined_string.as_bytes())
+    }
+
     pub fn check_crc(key: &String, value: &String, checksum: u64) -> bool {
         let combined_string = key.clone() + value;
         checksum == Crc::<u64>::new(&CRC_64_REDIS).checksum(combined_string.as_bytes())
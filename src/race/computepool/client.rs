@@ -1,11 +1,21 @@
-use super::directory::{self, ClientDirectory};
+use super::directory::{self, ClientDirectory, ClientEntry};
+use super::eviction::{self, Hotness};
 use crate::cfg::config::CONFIG;
+use crate::numa::mm::MemoryManager;
+use crate::race::common::error::RaceError;
 use crate::race::common::hash::{Hash, HashMethod};
 use crate::race::common::kvblock::KVBlock;
+use crate::race::common::options::OpOptions;
+use crate::race::common::retry::RetryState;
 use crate::race::common::utils::{self, RaceUtils};
-use crate::race::mempool::subtable::{CombinedBucket, Slot, SlotPos, Subtable};
+#[cfg(any(test, feature = "fault-injection"))]
+use crate::race::mempool::fault::CrashPoint;
+use crate::race::mempool::lease::LockIntent;
+use crate::race::mempool::subtable::{CombinedBucket, MigrationState, Slot, SlotPos, Subtable};
+use crate::race::mempool::verify::VerifyReport;
 use crate::race::mempool::{self, mempool::MemPool};
 use crate::KVBlockMem;
+use std::collections::{HashMap, HashSet};
 use std::mem::size_of;
 use std::process::exit;
 use std::sync::{Arc, Mutex, RwLock};
@@ -13,12 +23,152 @@ use std::sync::{Arc, Mutex, RwLock};
 pub struct Client {
     mempool: Arc<RwLock<MemPool>>,
     directory: ClientDirectory,
+    id: usize,
+    pin_count: usize,
+    // owner written into locked entries, it is the crashed client's id while recovering it
+    lock_owner: u8,
+    holds_lease: bool,
+    retry: RetryState,
+    // crashed by fault injection, others recover its locks and its id
+    crashed: bool,
+    // searches which found an item, every access_sample_num-th one is recorded
+    found_num: usize,
+}
+
+// one try of an operation, redo it when it races with others
+enum Attempt<T> {
+    Done(T),
+    Redo,
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        if self.crashed {
+            return;
+        }
+        self.mempool.read().unwrap().release_lease(self.id);
+        self.mempool.read().unwrap().unregister_client(self.id);
+    }
 }
 
 impl Client {
     pub fn new(mempool: Arc<RwLock<MemPool>>) -> Self {
         let directory = mempool.read().unwrap().get_directory();
-        Client { mempool, directory }
+        let id = mempool.read().unwrap().register_client();
+        Client {
+            mempool,
+            directory,
+            id,
+            pin_count: 0,
+            lock_owner: id as u8,
+            holds_lease: false,
+            retry: RetryState::new(),
+            crashed: false,
+            found_num: 0,
+        }
+    }
+
+    pub fn get_id(&self) -> usize {
+        self.id
+    }
+
+    // memory freed by others is kept until we leave, operations may nest by redoing
+    fn enter(&mut self) {
+        self.enter_with(&OpOptions::default());
+    }
+
+    // only the outermost operation sets the limits of retrying
+    fn enter_with(&mut self, options: &OpOptions) {
+        if self.pin_count == 0 {
+            self.mempool.read().unwrap().pin(self.id);
+            self.retry.reset(options);
+        }
+        self.pin_count += 1;
+    }
+
+    fn leave(&mut self) {
+        self.pin_count -= 1;
+        if self.pin_count == 0 {
+            // an operation failed halfway has given back its locks, do not keep its lease
+            if self.holds_lease && !self.crashed {
+                self.release_lease();
+            }
+            self.mempool.read().unwrap().unpin(self.id);
+        }
+    }
+
+    fn backoff(&mut self) -> Result<(), RaceError> {
+        self.check_mempool()?;
+        self.mempool.read().unwrap().yield_waiting();
+        self.retry.backoff()
+    }
+
+    fn check_mempool(&self) -> Result<(), RaceError> {
+        if self.crashed {
+            return Err(RaceError::Crashed);
+        }
+        if self.mempool.read().unwrap().is_failed() {
+            return Err(RaceError::PoolUnavailable);
+        }
+        Ok(())
+    }
+
+    // stop right here as if the process died, nothing is unlocked or released
+    #[cfg(any(test, feature = "fault-injection"))]
+    fn crash_point(&mut self, point: CrashPoint) -> Result<(), RaceError> {
+        if self
+            .mempool
+            .read()
+            .unwrap()
+            .get_fault_injector()
+            .is_some_and(|faults| faults.take_crash(self.id, point))
+        {
+            self.crashed = true;
+            return Err(RaceError::Crashed);
+        }
+        Ok(())
+    }
+
+    // a verb may fail spuriously under fault injection, retry as long as the slot holds old,
+    // false if someone else has changed the slot
+    fn retry_write_slot(
+        &mut self,
+        slot_pos: &SlotPos,
+        data: u64,
+        old: u64,
+    ) -> Result<bool, RaceError> {
+        loop {
+            if self.mempool.read().unwrap().write_slot(slot_pos, data, old) {
+                return Ok(true);
+            }
+            if self.mempool.read().unwrap().read_slot(slot_pos) != old {
+                return Ok(false);
+            }
+            self.backoff()?;
+        }
+    }
+
+    // update an entry we have locked, retried like retry_write_slot
+    fn push_entry(
+        &mut self,
+        index: usize,
+        old_data: u64,
+        new_data: u64,
+    ) -> Result<bool, RaceError> {
+        loop {
+            if self
+                .mempool
+                .read()
+                .unwrap()
+                .update_entry(index, old_data, new_data)
+            {
+                return Ok(true);
+            }
+            if self.mempool.read().unwrap().read_entry(index) != old_data {
+                return Ok(false);
+            }
+            self.backoff()?;
+        }
     }
 
     fn get_size(&self) -> usize {
@@ -29,6 +179,7 @@ impl Client {
         let index = RaceUtils::get_suffix(key, self.directory.global_depth as u8) as usize;
         let hash_1 = Hash::hash(key, HashMethod::CombinedBucket1) as usize;
         let hash_2 = Hash::hash(key, HashMethod::CombinedBucket2) as usize;
+        self.mempool.read().unwrap().yield_point();
         self.directory
             .get_entry_const(index)
             .get_combined_buckets(hash_1, hash_2)
@@ -95,14 +246,47 @@ impl Client {
         }
     }
 
+    // a null kv block keeps the value in the slot
+    fn get_slot_data(key: &String, val: &String, kv_block: *const KVBlockMem) -> u64 {
+        if kv_block.is_null() {
+            RaceUtils::set_inline_data(key, val)
+        } else {
+            RaceUtils::set_data(key, val, kv_block as u64)
+        }
+    }
+
+    fn can_inline(&self, key: &str, val: &str) -> bool {
+        self.mempool.read().unwrap().is_inline_enabled() && RaceUtils::can_inline(key, val)
+    }
+
+    // a block for the value, or null when it is kept in the slot
+    fn write_value(&mut self, key: &String, val: &str) -> Result<*const KVBlockMem, RaceError> {
+        if self.can_inline(key, val) {
+            return Ok(std::ptr::null());
+        }
+        let tracked = self.mempool.read().unwrap().is_access_tracked();
+        self.reserve_quota(KVBlockMem::get_length(key, val, tracked))?;
+        let kv_block = self
+            .mempool
+            .read()
+            .unwrap()
+            .write_kv(key.clone(), val.to_string());
+        if kv_block.is_null() {
+            return Err(RaceError::AllocationFailed);
+        }
+        Ok(kv_block)
+    }
+
+    // false if the slot is taken by others or the subtable is split meanwhile, kv_block is taken
+    // away if the item is left in the table
     fn write_slot(
         &mut self,
         slot_pos: &SlotPos,
         key: &String,
         val: &String,
-        kv_block: *const KVBlockMem,
-    ) -> bool {
-        let data = RaceUtils::set_data(key, val, kv_block as u64);
+        kv_block: &mut *const KVBlockMem,
+    ) -> Result<bool, RaceError> {
+        let data = Client::get_slot_data(key, val, *kv_block);
         if self.mempool.read().unwrap().write_slot(&slot_pos, data, 0) {
             // Reread and check whether the insert is correct
             let current_header = unsafe {
@@ -113,14 +297,18 @@ impl Client {
                 || RaceUtils::get_suffix(key, current_header.get_local_depth())
                     == current_header.get_suffix()
             {
-                true
+                Ok(true)
             } else {
                 // Insert during resizing, delete the wrong insertion and reinsert
-                self.mempool.read().unwrap().write_slot(&slot_pos, 0, data);
-                self.insert(key, val)
+                if let Err(e) = self.retry_write_slot(slot_pos, 0, data) {
+                    // the wrong insertion keeps its block, verify reports it
+                    *kv_block = std::ptr::null();
+                    return Err(e);
+                }
+                Ok(false)
             }
         } else {
-            self.insert(key, val)
+            Ok(false)
         }
     }
 
@@ -132,20 +320,65 @@ impl Client {
         kv_block: *const KVBlockMem,
         old: u64,
     ) -> bool {
-        let data = RaceUtils::set_data(key, val, kv_block as u64);
-        if self
-            .mempool
+        let data = Client::get_slot_data(key, val, kv_block);
+        self.mempool
             .read()
             .unwrap()
             .write_slot(&slot_pos, data, old)
+    }
+
+    fn get_source_combined_buckets(
+        &self,
+        key: &String,
+        cbs: &[CombinedBucket; 2],
+    ) -> Option<[CombinedBucket; 2]> {
+        if cbs
+            .iter()
+            .all(|cb| cb.main_bucket.header.get_migration_state() == MigrationState::Done as u8)
         {
-            true
-        } else {
-            self._update(key, val, kv_block)
+            return None;
+        }
+        // the subtable is the new one of an incremental split, items may still be in the old one
+        let local_depth = cbs[0].main_bucket.header.get_local_depth();
+        let source_index = RaceUtils::get_suffix(key, local_depth - 1) as usize;
+        let hash_1 = Hash::hash(key, HashMethod::CombinedBucket1) as usize;
+        let hash_2 = Hash::hash(key, HashMethod::CombinedBucket2) as usize;
+        self.mempool.read().unwrap().yield_point();
+        self.directory
+            .get_entry_const(source_index)
+            .get_combined_buckets(hash_1, hash_2)
+    }
+
+    // the value and the data of the slot it is found in
+    fn _search(
+        &mut self,
+        key: &String,
+        cbs: &[CombinedBucket; 2],
+    ) -> Result<Attempt<Option<(String, u64)>>, RaceError> {
+        if let Some(source_cbs) = self.get_source_combined_buckets(key, cbs) {
+            for source_cb in source_cbs.iter() {
+                if let Some((v, data)) = source_cb.get_by_key_and_data(key) {
+                    if RaceUtils::check_crc(&v.key, &v.value, v.crc64) {
+                        return Ok(Attempt::Done(Some((v.value, data))));
+                    } else {
+                        return Ok(Attempt::Redo);
+                    }
+                }
+            }
+            // read again, the item may have been moved after the first read
+            return match self.get_combined_buckets(key) {
+                Some(new_cbs) => self.search_combined_buckets(key, &new_cbs),
+                None => Ok(Attempt::Done(None)),
+            };
         }
+        self.search_combined_buckets(key, cbs)
     }
 
-    fn _search(&mut self, key: &String, cbs: &[CombinedBucket; 2]) -> Option<String> {
+    fn search_combined_buckets(
+        &mut self,
+        key: &String,
+        cbs: &[CombinedBucket; 2],
+    ) -> Result<Attempt<Option<(String, u64)>>, RaceError> {
         let remote_local_depth1 = cbs[0].main_bucket.header.get_local_depth();
         let remote_suffix1 = cbs[0].main_bucket.header.get_suffix();
         let suffix1 = RaceUtils::get_suffix(key, remote_local_depth1);
@@ -155,71 +388,172 @@ impl Client {
 
         let mut result = None;
         for i in 0..2 {
-            if let Some(v) = cbs[i].get_by_key(key) {
+            if let Some((v, data)) = cbs[i].get_by_key_and_data(key) {
                 if RaceUtils::check_crc(&v.key, &v.value, v.crc64) {
-                    result = Some(v.value);
+                    result = Some((v.value, data));
                     break;
                 } else {
-                    // TODO: consider putting current thread to sleep
-                    return self.search(key);
+                    // the item is being written or freed, read it again
+                    return Ok(Attempt::Redo);
                 }
             }
         }
         if remote_suffix1 == suffix1 && remote_suffix2 == suffix2 {
-            result
+            Ok(Attempt::Done(result))
         } else {
-            self.refresh_directory();
+            self.refresh_directory_for_key(key)?;
             if result.is_some() {
-                result
+                Ok(Attempt::Done(result))
             } else {
-                self.search(key)
+                Ok(Attempt::Redo)
             }
         }
     }
 
-    pub fn search(&mut self, key: &String) -> Option<String> {
-        match self.get_combined_buckets(key) {
-            Some(cbs) => self._search(key, &cbs),
-            None => None,
+    fn search_with_retry(&mut self, key: &String) -> Result<Option<String>, RaceError> {
+        self.check_mempool()?;
+        self.retry.check()?;
+        self.validate_directory(key)?;
+        loop {
+            let cbs = match self.get_combined_buckets(key) {
+                Some(cbs) => cbs,
+                None => return Ok(None),
+            };
+            if let Attempt::Done(result) = self._search(key, &cbs)? {
+                return Ok(result.map(|(value, data)| {
+                    self.touch(data);
+                    value
+                }));
+            }
+            self.backoff()?;
         }
     }
 
-    fn _insert(&mut self, key: &String, val: &String, kv_block: *const KVBlockMem) -> bool {
-        match self.get_combined_buckets(key) {
-            Some(cbs) => match self._search(key, &cbs) {
-                Some(_) => false,
-                None => match self.get_slot(key) {
-                    Some(sp) => self.write_slot(&sp, key, val, kv_block),
+    // tell the eviction policy about a found item and sample its access, data is the slot the
+    // search has found it in
+    fn touch(&mut self, data: u64) {
+        let policy = self.mempool.read().unwrap().get_eviction_policy();
+        let sample_num = self.mempool.read().unwrap().get_access_sample_num();
+        self.found_num += 1;
+        let sampled = sample_num != 0 && self.found_num.is_multiple_of(sample_num);
+        if policy.is_none() && !sampled {
+            return;
+        }
+        // an inline item has no block to keep its hotness
+        if (Slot { data }).is_inline() {
+            return;
+        }
+        let kv_block = (Slot { data }).get_kv_pointer();
+        if let Some(policy) = policy {
+            policy.on_access(kv_block);
+        }
+        if sampled {
+            let tick = self.mempool.read().unwrap().next_access_tick();
+            unsafe { (*kv_block).record_access(tick) };
+        }
+    }
+
+    pub fn search(&mut self, key: &String) -> Result<Option<String>, RaceError> {
+        self.search_with_options(key, &OpOptions::default())
+    }
+
+    pub fn search_with_options(
+        &mut self,
+        key: &String,
+        options: &OpOptions,
+    ) -> Result<Option<String>, RaceError> {
+        self.enter_with(options);
+        let result = self.search_with_retry(key);
+        self.leave();
+        result
+    }
+
+    fn _insert(
+        &mut self,
+        key: &String,
+        val: &String,
+        kv_block: &mut *const KVBlockMem,
+    ) -> Result<bool, RaceError> {
+        loop {
+            let cbs = match self.get_combined_buckets(key) {
+                Some(cbs) => cbs,
+                None => panic!("get candidate postion error"),
+            };
+            match self._search(key, &cbs)? {
+                Attempt::Done(Some(_)) => return Ok(false),
+                Attempt::Done(None) => match self.get_slot(key) {
+                    Some(sp) => {
+                        if self.write_slot(&sp, key, val, kv_block)? {
+                            return Ok(true);
+                        }
+                    }
                     None => {
-                        self.rehash(
-                            RaceUtils::get_suffix(key, self.directory.global_depth) as usize
-                        );
-                        self._insert(key, val, kv_block)
+                        // try to make room by displacement before splitting
+                        if !self.displace(key)? {
+                            self.rehash(
+                                RaceUtils::get_suffix(key, self.directory.global_depth) as usize
+                            )?;
+                        }
                     }
                 },
-            },
-            None => panic!("get candidate postion error"),
+                Attempt::Redo => {}
+            }
+            self.backoff()?;
         }
     }
 
-    pub fn insert(&mut self, key: &String, val: &String) -> bool {
-        let kv_block = self
-            .mempool
-            .read()
-            .unwrap()
-            .write_kv(key.clone(), val.clone());
-        if self._insert(key, val, kv_block) {
-            true
-        } else {
+    fn insert_with_retry(&mut self, key: &String, val: &String) -> Result<bool, RaceError> {
+        self.check_mempool()?;
+        self.retry.check()?;
+        self.validate_directory(key)?;
+        let mut kv_block = self.write_value(key, val)?;
+        let result = self._insert(key, val, &mut kv_block);
+        if result != Ok(true) && !kv_block.is_null() {
             self.mempool
                 .read()
                 .unwrap()
                 .free_kv(kv_block, unsafe { (*kv_block).get_total_length() });
-            false
         }
+        result
+    }
+
+    pub fn insert(&mut self, key: &String, val: &String) -> Result<bool, RaceError> {
+        self.insert_with_options(key, val, &OpOptions::default())
+    }
+
+    pub fn insert_with_options(
+        &mut self,
+        key: &String,
+        val: &String,
+        options: &OpOptions,
+    ) -> Result<bool, RaceError> {
+        self.enter_with(options);
+        let result = self.insert_with_retry(key, val);
+        self.leave();
+        result
+    }
+
+    fn get_slot_pos_and_data(
+        &self,
+        key: &String,
+        cbs: &[CombinedBucket; 2],
+    ) -> Option<(SlotPos, u64)> {
+        if let Some(source_cbs) = self.get_source_combined_buckets(key, cbs) {
+            if let Some(spd) = (0..2).find_map(|i| source_cbs[i].get_slot_pos_and_data(key, i)) {
+                return Some(spd);
+            }
+            // read again, the item may have been moved after the first read
+            let new_cbs = self.get_combined_buckets(key)?;
+            return (0..2).find_map(|i| new_cbs[i].get_slot_pos_and_data(key, i));
+        }
+        (0..2).find_map(|i| cbs[i].get_slot_pos_and_data(key, i))
     }
 
-    fn _delete(&mut self, key: &String, cbs: &[CombinedBucket; 2]) -> bool {
+    fn _delete(
+        &mut self,
+        key: &String,
+        cbs: &[CombinedBucket; 2],
+    ) -> Result<Attempt<bool>, RaceError> {
         let remote_local_depth1 = cbs[0].main_bucket.header.get_local_depth();
         let remote_suffix1 = cbs[0].main_bucket.header.get_suffix();
         let suffix1 = RaceUtils::get_suffix(key, remote_local_depth1);
@@ -229,106 +563,232 @@ impl Client {
 
         // Both local depth and suffix bits mismatch, refresh directory and redo!
         if remote_suffix1 != suffix1 || remote_suffix2 != suffix2 {
-            self.refresh_directory();
-            return self.delete(key);
+            self.refresh_directory_for_key(key)?;
+            return Ok(Attempt::Redo);
         }
 
-        let mut op_spd = None;
-        for i in 0..2 {
-            op_spd = cbs[i].get_slot_pos_and_data(key, i);
-            if op_spd.is_some() {
-                break;
-            }
-        }
-        if let Some(spd) = op_spd {
+        if let Some(spd) = self.get_slot_pos_and_data(key, cbs) {
             if self.mempool.read().unwrap().write_slot(&spd.0, 0, spd.1) {
-                self.mempool
-                    .read()
-                    .unwrap()
-                    .free_kv((Slot { data: spd.1 }).get_kv_pointer(), unsafe {
-                        (*(Slot { data: spd.1 }).get_kv_pointer()).get_total_length()
-                    });
-                true
+                self.mempool.read().unwrap().free_data(spd.1);
+                Ok(Attempt::Done(true))
             } else {
                 // CAS happens after "moving items" in resizing, refresh and redo!
-                self.refresh_directory();
-                self.delete(key)
+                self.refresh_directory_for_key(key)?;
+                Ok(Attempt::Redo)
             }
         } else {
             // At least suffix bits match, which means the key does not exist, then do nothing!
-            false
+            Ok(Attempt::Done(false))
         }
     }
 
-    pub fn delete(&mut self, key: &String) -> bool {
-        match self.get_combined_buckets(key) {
-            Some(cbs) => self._delete(key, &cbs),
-            None => panic!("get candidate position error"),
+    fn delete_with_retry(&mut self, key: &String) -> Result<bool, RaceError> {
+        self.check_mempool()?;
+        self.retry.check()?;
+        self.validate_directory(key)?;
+        loop {
+            let cbs = match self.get_combined_buckets(key) {
+                Some(cbs) => cbs,
+                None => panic!("get candidate position error"),
+            };
+            if let Attempt::Done(result) = self._delete(key, &cbs)? {
+                return Ok(result);
+            }
+            self.backoff()?;
         }
     }
 
-    fn _update(&mut self, key: &String, val: &String, kv_block: *const KVBlockMem) -> bool {
-        match self.get_combined_buckets(key) {
-            Some(cbs) => {
-                let remote_local_depth1 = cbs[0].main_bucket.header.get_local_depth();
-                let remote_suffix1 = cbs[0].main_bucket.header.get_suffix();
-                let suffix1 = RaceUtils::get_suffix(key, remote_local_depth1);
-                let remote_local_depth2 = cbs[1].main_bucket.header.get_local_depth();
-                let remote_suffix2 = cbs[1].main_bucket.header.get_suffix();
-                let suffix2 = RaceUtils::get_suffix(key, remote_local_depth2);
-
-                // Both local depth and suffix bits mismatch, refresh directory and redo!
-                if remote_suffix1 != suffix1 || remote_suffix2 != suffix2 {
-                    self.refresh_directory();
-                    return self._update(key, val, kv_block);
-                }
+    pub fn delete(&mut self, key: &String) -> Result<bool, RaceError> {
+        self.delete_with_options(key, &OpOptions::default())
+    }
 
-                let mut op_spd = None;
-                for i in 0..2 {
-                    op_spd = cbs[i].get_slot_pos_and_data(key, i);
-                    if op_spd.is_some() {
-                        break;
-                    }
-                }
+    pub fn delete_with_options(
+        &mut self,
+        key: &String,
+        options: &OpOptions,
+    ) -> Result<bool, RaceError> {
+        self.enter_with(options);
+        let result = self.delete_with_retry(key);
+        self.leave();
+        result
+    }
+
+    fn _update(
+        &mut self,
+        key: &String,
+        val: &String,
+        kv_block: *const KVBlockMem,
+    ) -> Result<bool, RaceError> {
+        loop {
+            let cbs = match self.get_combined_buckets(key) {
+                Some(cbs) => cbs,
+                None => panic!("get candidate postion error"),
+            };
+            let remote_local_depth1 = cbs[0].main_bucket.header.get_local_depth();
+            let remote_suffix1 = cbs[0].main_bucket.header.get_suffix();
+            let suffix1 = RaceUtils::get_suffix(key, remote_local_depth1);
+            let remote_local_depth2 = cbs[1].main_bucket.header.get_local_depth();
+            let remote_suffix2 = cbs[1].main_bucket.header.get_suffix();
+            let suffix2 = RaceUtils::get_suffix(key, remote_local_depth2);
+
+            // Both local depth and suffix bits mismatch, refresh directory and redo!
+            if remote_suffix1 != suffix1 || remote_suffix2 != suffix2 {
+                self.refresh_directory_for_key(key)?;
+                self.backoff()?;
+                continue;
+            }
 
-                if let Some(spd) = op_spd {
+            match self.get_slot_pos_and_data(key, &cbs) {
+                Some(spd) => {
                     if self.update_slot(&spd.0, key, val, kv_block, spd.1) {
-                        self.mempool.read().unwrap().free_kv(
-                            (Slot { data: spd.1 }).get_kv_pointer(),
-                            unsafe {
-                                (*(Slot { data: spd.1 }).get_kv_pointer()).get_total_length()
-                            },
-                        );
-                        true
-                    } else {
-                        // CAS happens after "moving items" in resizing, refresh and redo!
-                        self.refresh_directory();
-                        self._update(key, val, kv_block)
+                        self.mempool.read().unwrap().free_data(spd.1);
+                        return Ok(true);
                     }
-                } else {
-                    // At least suffix bits match, which means the key does not exist, then do nothing!
-                    false
+                    // CAS happens after "moving items" in resizing, refresh and redo!
+                    self.refresh_directory_for_key(key)?;
+                    self.backoff()?;
                 }
+                // At least suffix bits match, which means the key does not exist, then do nothing!
+                None => return Ok(false),
             }
-            None => panic!("get candidate postion error"),
         }
     }
 
-    pub fn update(&mut self, key: &String, val: &String) -> bool {
-        let kv_block = self
-            .mempool
-            .read()
-            .unwrap()
-            .write_kv(key.clone(), val.clone());
-        if self._update(key, val, kv_block) {
-            true
-        } else {
+    fn update_with_retry(&mut self, key: &String, val: &String) -> Result<bool, RaceError> {
+        self.check_mempool()?;
+        self.retry.check()?;
+        self.validate_directory(key)?;
+        let kv_block = self.write_value(key, val)?;
+        let result = self._update(key, val, kv_block);
+        if result != Ok(true) && !kv_block.is_null() {
             self.mempool
                 .read()
                 .unwrap()
                 .free_kv(kv_block, unsafe { (*kv_block).get_total_length() });
-            false
         }
+        result
+    }
+
+    pub fn update(&mut self, key: &String, val: &String) -> Result<bool, RaceError> {
+        self.update_with_options(key, val, &OpOptions::default())
+    }
+
+    pub fn update_with_options(
+        &mut self,
+        key: &String,
+        val: &String,
+        options: &OpOptions,
+    ) -> Result<bool, RaceError> {
+        self.enter_with(options);
+        let result = self.update_with_retry(key, val);
+        self.leave();
+        result
+    }
+
+    // replace the whole table with given items skipping the insert path, return the item number
+    pub fn bulk_load<I: IntoIterator<Item = (String, String)>>(
+        &mut self,
+        items: I,
+    ) -> Result<usize, RaceError> {
+        let items: Vec<(String, String)> = items.into_iter().collect();
+        let (dir, item_num) = self.mempool.read().unwrap().build_directory(items);
+        let old_dir = self.mempool.write().unwrap().swap_directory(dir);
+        self.mempool.read().unwrap().retire_directory(old_dir);
+        self.enter();
+        let result = self.refresh_directory();
+        self.leave();
+        result.map(|_| item_num)
+    }
+
+    // check the whole table, a repair first recovers crashed clients, and inserts the items
+    // taken out of a wrong subtable again
+    pub fn fsck(&mut self, repair: bool) -> Result<VerifyReport, RaceError> {
+        self.enter();
+        let result = self.fsck_inner(repair);
+        self.leave();
+        let report = result?;
+        for (key, value) in report.orphans.iter() {
+            // false if a copy is left in the right subtable
+            self.insert(key, value)?;
+        }
+        Ok(report)
+    }
+
+    fn fsck_inner(&mut self, repair: bool) -> Result<VerifyReport, RaceError> {
+        self.check_mempool()?;
+        if !repair {
+            return Ok(self.mempool.read().unwrap().verify());
+        }
+        // a repair holds the locks of all entries, no split, merge or resize of others runs
+        // meanwhile, and a resize done before the first lock is taken is not missed
+        self.wait_for_others()?;
+        let mut report = self
+            .mempool
+            .read()
+            .unwrap()
+            .release_orphan_locks(self.lock_owner);
+        self.refresh_directory()?;
+        loop {
+            self.lock_all()?;
+            if self.mempool.read().unwrap().get_directory_version() == self.directory.version {
+                break;
+            }
+            self.unlock_all();
+            self.refresh_directory()?;
+        }
+        report.append(self.mempool.read().unwrap().repair(self.lock_owner));
+        // entries may have changed under our locks, they are unlocked as they are now
+        self.refresh_directory_without_wait();
+        self.unlock_all();
+        self.release_lease();
+        if report.repaired_num > 0 {
+            // cached directories of others are refreshed
+            self.mempool.read().unwrap().increase_directory_epoch();
+        }
+        self.refresh_directory()?;
+        Ok(report)
+    }
+
+    // collect keys of every subtable, an item being migrated is reported once
+    pub fn scan_keys(&mut self) -> Result<Vec<String>, RaceError> {
+        self.enter();
+        let result = self.scan_keys_inner();
+        self.leave();
+        result
+    }
+
+    fn scan_keys_inner(&mut self) -> Result<Vec<String>, RaceError> {
+        self.check_mempool()?;
+        self.refresh_directory()?;
+        let mut subtables = HashSet::new();
+        let mut keys = HashSet::new();
+        for index in 0..self.get_size() {
+            let subtable =
+                self.directory.get_entry(index).get_subtable_pointer() as *const Subtable;
+            if !subtables.insert(subtable) {
+                continue;
+            }
+            for bucket_group_index in 0..CONFIG.bucket_group_num {
+                for bucket_index in 0..CONFIG.bucket_num {
+                    for slot_index in 0..CONFIG.slot_num {
+                        let slot_pos = SlotPos {
+                            subtable,
+                            bucket_group: bucket_group_index,
+                            bucket: bucket_index,
+                            header: 0,
+                            slot: slot_index,
+                        };
+                        match self.read_from_slot_pos_with_crc_check(&slot_pos)? {
+                            (_, Some(kv_data)) if kv_data.klen != 0 => {
+                                keys.insert(kv_data.key);
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            }
+        }
+        Ok(keys.into_iter().collect())
     }
 
     /**
@@ -338,41 +798,62 @@ impl Client {
         self.directory
             .get_entry(index as usize)
             .set_subtable_and_localdepth(pointer, local_depth);
-        let locked_data = self.directory.get_entry(index as usize).get_locked_data();
+        let locked_data = self
+            .directory
+            .get_entry(index)
+            .get_locked_data(self.lock_owner);
         self.mempool
             .read()
             .unwrap()
             .write_new_entry(index, locked_data);
     }
 
-    fn set_local_entry_and_push(&mut self, index: usize, pointer: u64, local_depth: u8) {
+    fn set_local_entry_and_push(
+        &mut self,
+        index: usize,
+        pointer: u64,
+        local_depth: u8,
+    ) -> Result<(), RaceError> {
         let old_data = self.directory.get_entry(index as usize).get_data();
-        let old_locked_data = self.directory.get_entry(index as usize).get_locked_data();
+        let old_locked_data = self
+            .directory
+            .get_entry(index)
+            .get_locked_data(self.lock_owner);
         self.directory
             .get_entry(index as usize)
             .set_subtable_and_localdepth(pointer, local_depth);
-        let new_locked_data = self.directory.get_entry(index as usize).get_locked_data();
-        self.mempool
-            .read()
-            .unwrap()
-            .update_entry(index, old_locked_data, new_locked_data);
+        let new_locked_data = self
+            .directory
+            .get_entry(index)
+            .get_locked_data(self.lock_owner);
+        self.push_entry(index, old_locked_data, new_locked_data)?;
+        Ok(())
     }
 
-    fn set_local_entries_and_push(&mut self, suffix: u64, pointer: u64, local_depth: u8) {
+    fn set_local_entries_and_push(
+        &mut self,
+        suffix: u64,
+        pointer: u64,
+        local_depth: u8,
+    ) -> Result<(), RaceError> {
         let mut index = suffix as usize;
         let old_size = self.get_size();
         while index < old_size {
-            self.set_local_entry_and_push(index, pointer, local_depth);
+            self.set_local_entry_and_push(index, pointer, local_depth)?;
             index = RaceUtils::plus_bit_to_suffix(index as u64, local_depth + 1) as usize;
         }
+        Ok(())
     }
 
-    fn refresh_directory(&mut self) {
+    fn refresh_directory(&mut self) -> Result<(), RaceError> {
         loop {
             self.directory = self.mempool.read().unwrap().get_directory();
             let mut all_free = true;
             for index in 0..self.get_size() {
-                if self.directory.get_entry_const(index).check_is_locked() {
+                // an empty entry shows the directory is being halved
+                if self.directory.get_entry_const(index).check_is_locked()
+                    || self.directory.get_entry_const(index).get_data() == 0
+                {
                     all_free = false;
                     break;
                 }
@@ -380,8 +861,11 @@ impl Client {
             if all_free {
                 break;
             }
+            self.wait_for_others()?;
+            self.backoff()?;
         }
         self.clear_all_lock_status();
+        Ok(())
     }
 
     fn refresh_directory_without_wait(&mut self) {
@@ -389,62 +873,178 @@ impl Client {
         self.clear_all_lock_status();
     }
 
-    /**
-     * Lock part
-     */
+    // a cached directory is trusted if no split, merge or resize happened since it was read,
+    // else the entry of the key is read and only its suffix is refreshed if it has changed
+    fn validate_directory(&mut self, key: &String) -> Result<(), RaceError> {
+        if self.mempool.read().unwrap().get_directory_epoch() == self.directory.epoch {
+            return Ok(());
+        }
+        let index = RaceUtils::get_suffix(key, self.directory.global_depth) as usize;
+        let remote_entry = ClientEntry {
+            data: self.mempool.read().unwrap().read_entry(index),
+        };
+        let entry = self.directory.get_entry_const(index);
+        if remote_entry.get_subtable_pointer() != entry.get_subtable_pointer()
+            || remote_entry.get_local_depth() != entry.get_local_depth()
+        {
+            self.refresh_entries(index)?;
+        }
+        Ok(())
+    }
 
-    fn clear_all_lock_status(&mut self) {
-        for index in 0..self.get_size() {
-            self.directory.get_entry(index).clear_lock_status();
+    // our own split, merge or resize is in our cached directory already, it stays trusted unless
+    // others have changed the directory since it was read
+    fn advance_epoch(&mut self) {
+        let epoch = self.mempool.read().unwrap().increase_directory_epoch();
+        if epoch == self.directory.epoch + 1 {
+            self.directory.epoch = epoch;
         }
     }
 
-    fn lock_base(&mut self, index: usize, is_try: bool) -> bool {
-        let old_data = self.directory.get_entry(index).get_data();
-        let try_times = 0;
+    fn refresh_directory_for_key(&mut self, key: &String) -> Result<(), RaceError> {
+        let index = RaceUtils::get_suffix(key, self.directory.global_depth) as usize;
+        self.refresh_entries(index)
+    }
+
+    // refresh only the entries sharing the suffix of index, unless the directory is resized
+    fn refresh_entries(&mut self, index: usize) -> Result<(), RaceError> {
         loop {
-            let result = self.mempool.read().unwrap().try_lock_entry(index, old_data);
-            match result {
-                Ok(_) => return true,
-                Err(new_data) => {
-                    if is_try && RaceUtils::check_is_locked(new_data) {
-                        return false;
-                    }
+            let version = self.mempool.read().unwrap().get_directory_version();
+            if version != self.directory.version {
+                return self.refresh_directory();
+            }
+
+            // the shorter depth covers both a split and a merge of this suffix
+            let remote_local_depth = ClientEntry {
+                data: self.mempool.read().unwrap().read_entry(index),
+            }
+            .get_local_depth();
+            let local_depth = std::cmp::min(
+                remote_local_depth,
+                self.directory.get_entry(index).get_local_depth(),
+            );
+            let mut entries = Vec::new();
+            let mut entry_index = RaceUtils::restrict_suffix_to(index as u64, local_depth) as usize;
+            while entry_index < self.get_size() {
+                let data = self.mempool.read().unwrap().read_entry(entry_index);
+                if RaceUtils::check_is_locked(data) || data == 0 {
+                    break;
                 }
+                entries.push((entry_index, data));
+                entry_index =
+                    RaceUtils::plus_bit_to_suffix(entry_index as u64, local_depth + 1) as usize;
             }
-            self.refresh_directory_without_wait();
-            if is_try && try_times >= CONFIG.max_try_lock_times {
-                return false;
+
+            // all entries are free and nobody resized the directory meanwhile
+            if entry_index >= self.get_size()
+                && version == self.mempool.read().unwrap().get_directory_version()
+            {
+                for (entry_index, data) in entries {
+                    self.directory.get_entry(entry_index).set_data(data);
+                }
+                return Ok(());
             }
+            self.wait_for_others()?;
+            self.backoff()?;
         }
-        false
     }
 
-    fn try_lock(&mut self, index: usize) -> bool {
-        self.lock_base(index, true)
+    fn refresh_entry_without_wait(&mut self, index: usize) {
+        let version = self.mempool.read().unwrap().get_directory_version();
+        if version != self.directory.version {
+            self.refresh_directory_without_wait();
+            return;
+        }
+        let data = self.mempool.read().unwrap().read_entry(index);
+        self.directory.get_entry(index).set_data(data);
+        self.directory.get_entry(index).clear_lock_status();
     }
 
-    fn lock(&mut self, index: usize) {
-        self.lock_base(index, false);
+    fn flush_directory(&mut self) {
+        if self.mempool.read().unwrap().get_directory_version() != self.directory.version {
+            self.refresh_directory_without_wait();
+        }
+    }
+
+    /**
+     * Lock part
+     */
+
+    fn clear_all_lock_status(&mut self) {
+        for index in 0..self.get_size() {
+            self.directory.get_entry(index).clear_lock_status();
+        }
+    }
+
+    fn lock_base(&mut self, index: usize, is_try: bool) -> Result<bool, RaceError> {
+        let mut try_times = 0;
+        loop {
+            let old_data = self.directory.get_entry(index).get_data();
+            let result =
+                self.mempool
+                    .read()
+                    .unwrap()
+                    .try_lock_entry(index, old_data, self.lock_owner);
+            match result {
+                Ok(_) => {
+                    self.renew_lease();
+                    return Ok(true);
+                }
+                Err(new_data) => {
+                    if is_try && RaceUtils::check_is_locked(new_data) {
+                        return Ok(false);
+                    }
+                    if RaceUtils::check_is_locked(new_data) {
+                        self.wait_for_others()?;
+                    }
+                }
+            }
+            self.refresh_entry_without_wait(index);
+            try_times += 1;
+            if is_try && try_times >= CONFIG.max_try_lock_times {
+                return Ok(false);
+            }
+            self.backoff()?;
+        }
+    }
+
+    fn try_lock(&mut self, index: usize) -> Result<bool, RaceError> {
+        self.lock_base(index, true)
+    }
+
+    fn lock(&mut self, index: usize) -> Result<(), RaceError> {
+        self.lock_base(index, false).map(|_| ())
     }
 
     fn unlock(&mut self, index: usize) {
-        let locked_data = self.directory.get_entry(index).get_locked_data();
+        // a crashed client leaves its locks to recovery, whatever path it fails on
+        if self.crashed {
+            return;
+        }
+        let locked_data = self
+            .directory
+            .get_entry(index)
+            .get_locked_data(self.lock_owner);
         self.mempool
             .read()
             .unwrap()
             .unlock_entry(index, locked_data);
     }
 
-    fn lock_all(&mut self) {
+    fn lock_all(&mut self) -> Result<(), RaceError> {
         let mut now_index = 0;
         loop {
-            self.lock(now_index);
+            if let Err(e) = self.lock(now_index) {
+                // entries not locked by us do not match our lock data, so unlocking them is harmless
+                self.unlock_all();
+                return Err(e);
+            }
             now_index += 1;
             if now_index >= self.get_size() {
                 break;
             }
         }
+        Ok(())
     }
 
     fn unlock_all(&mut self) {
@@ -458,27 +1058,32 @@ impl Client {
         }
     }
 
-    fn lock_suffix(&mut self, suffix: u64, local_depth: u8) {
+    fn lock_suffix(&mut self, suffix: u64, local_depth: u8) -> Result<(), RaceError> {
         let mut index = suffix as usize;
         loop {
-            self.lock(index);
+            if let Err(e) = self.lock(index) {
+                self.unlock_suffix(suffix);
+                return Err(e);
+            }
             index = RaceUtils::plus_bit_to_suffix(index as u64, local_depth + 1) as usize;
             if index >= self.get_size() {
                 break;
             }
         }
+        Ok(())
     }
 
-    fn lock_suffix_without_self(&mut self, suffix: u64, local_depth: u8) {
+    fn lock_suffix_without_self(&mut self, suffix: u64, local_depth: u8) -> Result<(), RaceError> {
         let mut index = suffix as usize;
         index = RaceUtils::plus_bit_to_suffix(index as u64, local_depth + 1) as usize;
         loop {
             if index >= self.get_size() {
                 break;
             }
-            self.lock(index);
+            self.lock(index)?;
             index = RaceUtils::plus_bit_to_suffix(index as u64, local_depth + 1) as usize;
         }
+        Ok(())
     }
 
     fn lock_suffix_helper(
@@ -487,24 +1092,43 @@ impl Client {
         new_index: usize,
         local_depth: u8,
         is_first: bool,
-    ) {
+    ) -> Result<(), RaceError> {
         if is_first {
-            self.lock(old_index);
-            self.lock(new_index);
+            self.lock(old_index)?;
+            self.lock(new_index)?;
         } else {
-            self.lock_suffix_without_self(old_index as u64, local_depth);
-            self.lock_suffix_without_self(new_index as u64, local_depth);
+            self.lock_suffix_without_self(old_index as u64, local_depth)?;
+            self.lock_suffix_without_self(new_index as u64, local_depth)?;
         }
+        Ok(())
     }
 
-    fn lock_suffix_and_flush(&mut self, old_index: usize, new_index: usize, local_depth: u8) {
-        self.lock_suffix_helper(old_index, new_index, local_depth, true);
-        self.get_directory();
-        self.lock_suffix_helper(old_index, new_index, local_depth, false);
+    fn lock_suffix_and_flush(
+        &mut self,
+        old_index: usize,
+        new_index: usize,
+        local_depth: u8,
+    ) -> Result<(), RaceError> {
+        let result = self
+            .lock_suffix_helper(old_index, new_index, local_depth, true)
+            .and_then(|_| {
+                self.flush_directory();
+                self.lock_suffix_helper(old_index, new_index, local_depth, false)
+            });
+        if result.is_err() {
+            self.unlock_suffix_with_depth(old_index as u64, local_depth);
+            self.unlock_suffix_with_depth(new_index as u64, local_depth);
+        }
+        result
     }
 
     fn unlock_suffix(&mut self, suffix: u64) {
         let local_depth = self.directory.get_entry(suffix as usize).get_local_depth();
+        self.unlock_suffix_with_depth(suffix, local_depth);
+    }
+
+    // the entries locked with a local depth, the flushed directory may show another one
+    fn unlock_suffix_with_depth(&mut self, suffix: u64, local_depth: u8) {
         let mut index = suffix as usize;
         loop {
             self.unlock(index);
@@ -515,16 +1139,18 @@ impl Client {
         }
     }
 
-    fn double_size(&mut self) {
+    fn double_size(&mut self) -> Result<(), RaceError> {
         // lock all
         let old_size = self.get_size();
-        self.lock_all();
+        self.lock_all()?;
         let new_size = self.get_size();
         if old_size != new_size {
             // shows someone has update the directory
             self.unlock_all();
-            return;
+            self.release_lease();
+            return Ok(());
         }
+        self.set_intent(LockIntent::Resize, 0, self.directory.global_depth, 0);
 
         // begin double size now!
         // set directory
@@ -543,177 +1169,255 @@ impl Client {
         // set global depth
         self.directory.global_depth += 1;
         self.mempool.read().unwrap().increase_global_depth();
+        self.directory.version += 1;
+        self.mempool.read().unwrap().increase_directory_version();
+        self.advance_epoch();
 
         // unlock all
         self.unlock_all();
+        self.release_lease();
+        Ok(())
     }
 
-    fn read_from_slot_pos_with_crc_check(&mut self, slot_pos: &SlotPos) -> (u64, Option<KVBlock>) {
+    fn read_from_slot_pos_with_crc_check(
+        &mut self,
+        slot_pos: &SlotPos,
+    ) -> Result<(u64, Option<KVBlock>), RaceError> {
         let mut data = self.mempool.read().unwrap().read_slot(&slot_pos);
         let mut kv_data_op = Slot { data }.get_kv();
         loop {
             if let Some(kv_data) = &kv_data_op {
                 if kv_data.klen == 0 {
                     // there is no data in this slot, we can skip it
-                    return (data, kv_data_op);
+                    return Ok((data, kv_data_op));
                 }
                 if RaceUtils::check_crc(&kv_data.key, &kv_data.value, kv_data.crc64) {
-                    return (data, kv_data_op);
+                    return Ok((data, kv_data_op));
                 }
             } else {
-                return (data, kv_data_op);
+                return Ok((data, kv_data_op));
             }
+            self.backoff()?;
             data = self.mempool.read().unwrap().read_slot(&slot_pos);
             kv_data_op = Slot { data }.get_kv();
         }
-        return (data, kv_data_op);
     }
 
-    fn move_items(&mut self, old_index: usize, new_index: usize, local_depth: u8) {
-        for bucket_group_index in 0..CONFIG.bucket_group_num {
-            for bucket_index in 0..CONFIG.bucket_num {
-                for slot_index in 0..CONFIG.slot_num {
-                    let slot_pos = SlotPos {
-                        subtable: self.directory.get_entry(old_index).get_subtable_pointer()
-                            as *const Subtable,
-                        bucket_group: bucket_group_index,
-                        bucket: bucket_index,
-                        header: 0,
-                        slot: slot_index,
-                    };
+    // an error never leaves the item in both subtables, should_move is given the directory hash
+    // of the key, the item goes to preferred if it is free and else to the first free slot of
+    // its buckets, return the slot it is moved to
+    fn move_item(
+        &mut self,
+        slot_pos: &SlotPos,
+        subtable: *const Subtable,
+        preferred: Option<&SlotPos>,
+        should_move: impl Fn(u64) -> bool,
+    ) -> Result<Option<SlotPos>, RaceError> {
+        // read from this slot
+        let (mut data, mut kv_data_op) = self.read_from_slot_pos_with_crc_check(slot_pos)?;
+        loop {
+            let slot = Slot { data };
+            let kv_data = match &kv_data_op {
+                Some(kv_data) if kv_data.klen != 0 => kv_data,
+                // there is no data in this slot, or someone has deleted it
+                _ => return Ok(None),
+            };
+            if !should_move(Hash::hash(&kv_data.key, HashMethod::Directory)) {
+                // don't need to move
+                return Ok(None);
+            }
 
-                    // read from this slot
-                    let (mut data, mut kv_data_op) = self.read_from_slot_pos_with_crc_check(&slot_pos);
+            // insert to new subtable, an inline item is copied as it is
+            let new_data = if slot.is_inline() {
+                data
+            } else {
+                let new_kv_block = self
+                    .mempool
+                    .read()
+                    .unwrap()
+                    .write_kv(kv_data.key.clone(), kv_data.value.clone());
+                if new_kv_block.is_null() {
+                    return Err(RaceError::AllocationFailed);
+                }
+                unsafe { (*new_kv_block).copy_access(&*slot.get_kv_pointer()) };
+                RaceUtils::set_data(&kv_data.key, &kv_data.value, new_kv_block as u64)
+            };
+            let key = kv_data.key.clone();
+            let new_slot_pos = match self.place_copy(subtable, preferred, &key, new_data) {
+                Ok(new_slot_pos) => new_slot_pos,
+                Err(e) => {
+                    if new_data != data {
+                        self.mempool.read().unwrap().free_data(new_data);
+                    }
+                    return Err(e);
+                }
+            };
 
-                    if let Some(kv_data) = &kv_data_op {
-                        if kv_data.klen == 0 {
-                            // there is no data in this slot, we can skip it
-                            continue;
-                        }
+            // free old data
+            if self.mempool.read().unwrap().write_slot(slot_pos, 0, data) {
+                if new_data != data {
+                    self.mempool.read().unwrap().free_data(data);
+                }
+                return Ok(Some(new_slot_pos));
+            }
 
-                        let hash_key = Hash::hash(&kv_data.key, HashMethod::Directory);
-                        let desired_new_index =
-                            RaceUtils::get_suffix(&kv_data.key, self.directory.global_depth as u8)
-                                as usize;
-
-                        if self
-                            .directory
-                            .get_entry(desired_new_index)
-                            .get_subtable_pointer()
-                            != self.directory.get_entry(old_index).get_subtable_pointer()
-                            && self
-                                .directory
-                                .get_entry(desired_new_index)
-                                .get_subtable_pointer()
-                                != self.directory.get_entry(new_index).get_subtable_pointer()
-                        {
-                            print!(
-                                "desired_new_index: {}, old_index: {}, new_index: {}, hash_key: {}, key: {}, global_depth: {}",
-                                desired_new_index, old_index, new_index, hash_key, kv_data.key, self.directory.global_depth
-                            );
-                            print!(
-                                "old_pointer: {}, new_pointer: {}, desired_new_pointer: {}",
-                                self.directory.get_entry(old_index).get_subtable_pointer(),
-                                self.directory.get_entry(new_index).get_subtable_pointer(),
-                                self.directory
-                                    .get_entry(desired_new_index)
-                                    .get_subtable_pointer()
-                            );
-                            panic!("move items error")
-                        }
+            // someone has updated or deleted it, withdraw the copy and redo; a copy taken away
+            // meanwhile is freed by whoever took it
+            if self.retry_write_slot(&new_slot_pos, 0, new_data)? && new_data != data {
+                self.mempool.read().unwrap().free_data(new_data);
+            }
+            self.backoff()?;
+            (data, kv_data_op) = self.read_from_slot_pos_with_crc_check(slot_pos)?;
+        }
+    }
 
-                        if self
-                            .directory
-                            .get_entry(desired_new_index)
-                            .get_subtable_pointer()
-                            != self.directory.get_entry(new_index).get_subtable_pointer()
-                        {
-                            // don't need to move
-                            continue;
-                        }
+    // write the copy of a moving item to preferred if it is free, and else to the first free slot
+    // of its buckets in subtable
+    fn place_copy(
+        &mut self,
+        subtable: *const Subtable,
+        preferred: Option<&SlotPos>,
+        key: &String,
+        new_data: u64,
+    ) -> Result<SlotPos, RaceError> {
+        if let Some(preferred) = preferred {
+            if self.retry_write_slot(preferred, new_data, 0)? {
+                return Ok(SlotPos {
+                    subtable,
+                    bucket_group: preferred.bucket_group,
+                    bucket: preferred.bucket,
+                    header: 0,
+                    slot: preferred.slot,
+                });
+            }
+        }
+        loop {
+            // writers have filled the buckets of the item
+            let new_slot_pos = self
+                .find_empty_slot(subtable, key)
+                .ok_or(RaceError::NoFreeSlot)?;
+            if self.retry_write_slot(&new_slot_pos, new_data, 0)? {
+                return Ok(new_slot_pos);
+            }
+            self.backoff()?;
+        }
+    }
 
-                        // insert to new subtable
-                        let new_slot_pos = SlotPos {
-                            subtable: self.directory.get_entry(new_index).get_subtable_pointer()
-                                as *const Subtable,
-                            bucket_group: bucket_group_index,
-                            bucket: bucket_index,
-                            header: 0,
-                            slot: slot_index,
-                        };
-                        let mut new_kv_block = self
-                            .mempool
-                            .read()
-                            .unwrap()
-                            .write_kv(kv_data.key.clone(), kv_data.value.clone());
-
-                        let mut new_data =
-                            RaceUtils::set_data(&kv_data.key, &kv_data.value, new_kv_block as u64);
-
-                        self.mempool
-                            .read()
-                            .unwrap()
-                            .write_slot(&new_slot_pos, new_data, 0);
-
-                        // free old data
-                        loop {
-                            let write_res =
-                                self.mempool.read().unwrap().write_slot(&slot_pos, 0, data);
-                            if write_res {
-                                self.mempool.read().unwrap().free_kv(
-                                    (Slot { data }).get_kv_pointer(),
-                                    unsafe {
-                                        (*(Slot { data }).get_kv_pointer()).get_total_length()
-                                    },
-                                );
-                                break;
-                            }
-                            (data, kv_data_op) = self.read_from_slot_pos_with_crc_check(&slot_pos);
-                            if let Some(kv_data) = kv_data_op {
-                                if kv_data.klen == 0 {
-                                    // there is no data in this slot, someone has deleted it
-                                    self.mempool.read().unwrap().write_slot(
-                                        &new_slot_pos,
-                                        0,
-                                        new_data,
-                                    );
-                                    self.mempool.read().unwrap().free_kv(
-                                        (Slot { data: new_data }).get_kv_pointer(),
-                                        unsafe {
-                                            (*(Slot { data: new_data }).get_kv_pointer())
-                                                .get_total_length()
-                                        },
-                                    );
-                                    break;
-                                }
-                                // someone update it
-                                new_kv_block = self
-                                    .mempool
-                                    .read()
-                                    .unwrap()
-                                    .write_kv(kv_data.key.clone(), kv_data.value.clone());
-
-                                new_data = RaceUtils::set_data(
-                                    &kv_data.key,
-                                    &kv_data.value,
-                                    new_kv_block as u64,
-                                );
-
-                                self.mempool
-                                    .read()
-                                    .unwrap()
-                                    .write_slot(&new_slot_pos, new_data, 0);
-                            } else {
-                                panic!("get kv error");
-                            }
-                        }
-                    }
+    fn migrate_bucket_group(
+        &mut self,
+        old_pointer: *const Subtable,
+        new_pointer: *const Subtable,
+        new_index: usize,
+        local_depth: u8,
+        bucket_group_index: usize,
+    ) -> Result<(), RaceError> {
+        while !self
+            .mempool
+            .read()
+            .unwrap()
+            .try_claim_migration(new_pointer, bucket_group_index)
+        {
+            // done, or someone else is moving this bucket group, wait for it,
+            // and take it over if the mover gives it back
+            self.renew_lease();
+            if self
+                .mempool
+                .read()
+                .unwrap()
+                .get_migration_state(new_pointer, bucket_group_index)
+                == MigrationState::Done as u8
+            {
+                return Ok(());
+            }
+            self.wait_for_others()?;
+            self.backoff()?;
+        }
+        self.set_intent(
+            LockIntent::Migrate,
+            bucket_group_index,
+            local_depth,
+            new_pointer as u64,
+        );
+        for bucket_index in 0..CONFIG.bucket_num {
+            for slot_index in 0..CONFIG.slot_num {
+                let slot_pos = SlotPos {
+                    subtable: old_pointer,
+                    bucket_group: bucket_group_index,
+                    bucket: bucket_index,
+                    header: 0,
+                    slot: slot_index,
+                };
+                // prefer the same position, the new subtable is empty but for new items
+                let same_slot_pos = SlotPos {
+                    subtable: new_pointer,
+                    bucket_group: bucket_group_index,
+                    bucket: bucket_index,
+                    header: 0,
+                    slot: slot_index,
+                };
+                let result = self.move_item(&slot_pos, new_pointer, Some(&same_slot_pos), |hash| {
+                    RaceUtils::restrict_suffix_to(hash, local_depth) == new_index as u64
+                });
+                if let Err(e) = result {
+                    // give the bucket group back, moved items are not moved again
+                    self.mempool
+                        .read()
+                        .unwrap()
+                        .reset_migration(new_pointer, bucket_group_index);
+                    return Err(e);
                 }
             }
+            #[cfg(any(test, feature = "fault-injection"))]
+            self.crash_point(CrashPoint::DuringMigration)?;
         }
+        self.mempool
+            .read()
+            .unwrap()
+            .finish_migration(new_pointer, bucket_group_index);
+        Ok(())
     }
 
-    fn split_entry(&mut self, old_index: usize) {
+    fn migrate_items(
+        &mut self,
+        old_pointer: *const Subtable,
+        new_pointer: *const Subtable,
+        new_index: usize,
+        local_depth: u8,
+    ) -> Result<(), RaceError> {
+        // move bucket groups in small steps, readers and writers consult both subtables meanwhile,
+        // the lease is renewed for each of them, so that a long split is not taken for a crash
+        for bucket_group_index in 0..CONFIG.bucket_group_num {
+            self.renew_lease();
+            self.migrate_bucket_group(
+                old_pointer,
+                new_pointer,
+                new_index,
+                local_depth,
+                bucket_group_index,
+            )?;
+            if (bucket_group_index + 1) % CONFIG.migration_step_group_num == 0 {
+                std::thread::yield_now();
+            }
+        }
+        Ok(())
+    }
+
+    fn finish_migration(&mut self, index: usize, local_depth: u8) -> Result<(), RaceError> {
+        // help an unfinished incremental split of this suffix pair
+        let old_index = RaceUtils::restrict_suffix_to(index as u64, local_depth - 1) as usize;
+        let new_index =
+            RaceUtils::get_new_suffix_from_old(old_index as u64, local_depth - 1) as usize;
+        let old_pointer =
+            self.directory.get_entry(old_index).get_subtable_pointer() as *const Subtable;
+        let new_pointer =
+            self.directory.get_entry(new_index).get_subtable_pointer() as *const Subtable;
+        if old_pointer == new_pointer || !self.mempool.read().unwrap().is_migrating(new_pointer) {
+            return Ok(());
+        }
+        self.migrate_items(old_pointer, new_pointer, new_index, local_depth)
+    }
+
+    fn split_entry(&mut self, old_index: usize) -> Result<(), RaceError> {
         // get old depth from old index
         let old_depth = self.directory.get_entry(old_index).get_local_depth();
 
@@ -726,19 +1430,24 @@ impl Client {
             panic!("new_index out of range");
         }
 
-        // create new subtable
+        // create new subtable, all bucket groups wait for migration before it is published
         let new_pointer = self
             .mempool
             .read()
             .unwrap()
             .new_subtable(old_depth + 1, new_index as u64) as u64;
+        self.mempool
+            .read()
+            .unwrap()
+            .start_migration(new_pointer as *const Subtable);
+        self.set_intent(LockIntent::Split, old_index, old_depth, new_pointer);
 
         // get old pointer
         let old_pointer = self.directory.get_entry(old_index).get_subtable_pointer() as u64;
 
         // set entry
-        self.set_local_entries_and_push(old_index as u64, old_pointer, old_depth + 1);
-        self.set_local_entries_and_push(new_index as u64, new_pointer, old_depth + 1);
+        self.set_local_entries_and_push(old_index as u64, old_pointer, old_depth + 1)?;
+        self.set_local_entries_and_push(new_index as u64, new_pointer, old_depth + 1)?;
 
         // do not forget to change old subtable
         self.mempool.read().unwrap().set_subtable_header(
@@ -746,9 +1455,11 @@ impl Client {
             old_depth + 1,
             old_index as u64,
         );
+        self.advance_epoch();
+        Ok(())
     }
 
-    fn rehash(&mut self, rehash_index: usize) {
+    fn rehash(&mut self, rehash_index: usize) -> Result<(), RaceError> {
         // get real old index
         let old_index = RaceUtils::restrict_suffix_to(
             rehash_index as u64,
@@ -766,14 +1477,14 @@ impl Client {
         }
 
         if old_size <= new_index {
-            self.double_size();
+            self.double_size()?;
         }
 
         // we must get local depth first
         let old_depth = self.directory.get_entry(old_index).get_local_depth();
 
         // we must try lock and get newest global depth
-        self.lock_suffix_and_flush(old_index, new_index, old_depth + 1);
+        self.lock_suffix_and_flush(old_index, new_index, old_depth + 1)?;
 
         // get local depth again
         let new_depth = self.directory.get_entry(old_index).get_local_depth();
@@ -781,20 +1492,1088 @@ impl Client {
             // someone has changed the directory
             self.unlock_suffix(old_index as u64);
             self.unlock_suffix(new_index as u64);
-            return;
+            self.release_lease();
+            return Ok(());
         }
 
-        // split now!
-        self.split_entry(old_index);
+        // the last split of this suffix must be finished before splitting again
+        if let Err(e) = self.finish_migration(old_index, old_depth) {
+            self.unlock_suffix(old_index as u64);
+            self.unlock_suffix(new_index as u64);
+            self.release_lease();
+            return Err(e);
+        }
 
-        // move items from old subtable to new subtable
-        // we must have lock, to prevent other thread from changing the directory
-        // self.move_items(old_index);
-        self.move_items(old_index, new_index, old_depth + 1);
+        // split now!
+        self.set_intent(LockIntent::Split, old_index, old_depth, 0);
+        #[cfg(any(test, feature = "fault-injection"))]
+        self.crash_point(CrashPoint::AfterLock)?;
+        if let Err(e) = self.split_entry(old_index) {
+            // the split is published halfway, recovery finishes it
+            self.abandon();
+            return Err(e);
+        }
+        let old_pointer =
+            self.directory.get_entry(old_index).get_subtable_pointer() as *const Subtable;
+        let new_pointer =
+            self.directory.get_entry(new_index).get_subtable_pointer() as *const Subtable;
+        #[cfg(any(test, feature = "fault-injection"))]
+        self.crash_point(CrashPoint::AfterSplitEntry)?;
 
         // unlock suffix
         self.unlock_suffix(old_index as u64);
         self.unlock_suffix(new_index as u64);
+        #[cfg(any(test, feature = "fault-injection"))]
+        self.crash_point(CrashPoint::BeforeMigration)?;
+
+        // move items from old subtable to new subtable without lock, bucket group by bucket group,
+        // the lease of a failed migration is released when we leave
+        self.migrate_items(old_pointer, new_pointer, new_index, old_depth + 1)?;
+        self.release_lease();
+        Ok(())
+    }
+
+    /**
+     * Displacement part
+     */
+    fn get_alternate_buckets(
+        key: &String,
+        bucket_group: usize,
+        bucket: usize,
+    ) -> [(usize, usize); 2] {
+        let hash_1 = Hash::hash(key, HashMethod::CombinedBucket1) as usize;
+        let hash_2 = Hash::hash(key, HashMethod::CombinedBucket2) as usize;
+        // the main bucket shows which hash the item uses, the overflow bucket is shared
+        if bucket == 0 || (bucket == 1 && bucket_group == hash_1) {
+            [(hash_2, 2), (hash_2, 1)]
+        } else {
+            [(hash_1, 0), (hash_1, 1)]
+        }
+    }
+
+    fn try_displace_slot(&mut self, slot_pos: &SlotPos) -> Result<bool, RaceError> {
+        let (data, kv_data_op) = self.read_from_slot_pos_with_crc_check(slot_pos)?;
+        let kv_data = match kv_data_op {
+            Some(kv_data) if kv_data.klen != 0 => kv_data,
+            _ => return Ok(false),
+        };
+        for (bucket_group, bucket) in
+            Client::get_alternate_buckets(&kv_data.key, slot_pos.bucket_group, slot_pos.bucket)
+        {
+            if bucket_group == slot_pos.bucket_group {
+                // both hashes point to the same bucket group, nowhere to go
+                return Ok(false);
+            }
+            for slot in 0..CONFIG.slot_num {
+                let new_slot_pos = SlotPos {
+                    subtable: slot_pos.subtable,
+                    bucket_group,
+                    bucket,
+                    header: 0,
+                    slot,
+                };
+                // copy the slot to its alternate position, the kv block is shared
+                if !self
+                    .mempool
+                    .read()
+                    .unwrap()
+                    .write_slot(&new_slot_pos, data, 0)
+                {
+                    continue;
+                }
+                if self.mempool.read().unwrap().write_slot(slot_pos, 0, data) {
+                    return Ok(true);
+                }
+                // someone has updated or deleted it, withdraw the copy
+                self.retry_write_slot(&new_slot_pos, 0, data)?;
+                return Ok(false);
+            }
+        }
+        Ok(false)
+    }
+
+    fn displace(&mut self, key: &String) -> Result<bool, RaceError> {
+        let index = RaceUtils::get_suffix(key, self.directory.global_depth) as usize;
+        let local_depth = self.directory.get_entry(index).get_local_depth();
+        let suffix = RaceUtils::restrict_suffix_to(index as u64, local_depth);
+
+        // lock the suffix, so that no one splits the subtable while items move inside it
+        self.lock_suffix(suffix, local_depth)?;
+        if self.directory.get_entry(index).get_local_depth() != local_depth {
+            // someone has changed the directory
+            self.unlock_suffix(suffix);
+            self.release_lease();
+            return Ok(false);
+        }
+        if let Err(e) = self.finish_migration(index, local_depth) {
+            self.unlock_suffix(suffix);
+            self.release_lease();
+            return Err(e);
+        }
+
+        let subtable = self.directory.get_entry(index).get_subtable_pointer() as *const Subtable;
+        self.set_intent(LockIntent::Displace, index, local_depth, subtable as u64);
+        let hash_1 = Hash::hash(key, HashMethod::CombinedBucket1) as usize;
+        let hash_2 = Hash::hash(key, HashMethod::CombinedBucket2) as usize;
+        let mut try_times = 0;
+        let mut displaced = Ok(false);
+        'outer: for (bucket_group, bucket) in [(hash_1, 0), (hash_1, 1), (hash_2, 2), (hash_2, 1)] {
+            for slot in 0..CONFIG.slot_num {
+                if try_times >= CONFIG.max_try_displace_times {
+                    break 'outer;
+                }
+                try_times += 1;
+                let slot_pos = SlotPos {
+                    subtable,
+                    bucket_group,
+                    bucket,
+                    header: 0,
+                    slot,
+                };
+                displaced = self.try_displace_slot(&slot_pos);
+                if displaced != Ok(false) {
+                    break 'outer;
+                }
+            }
+        }
+
+        self.unlock_suffix(suffix);
+        self.release_lease();
+        displaced
+    }
+
+    /**
+     * Lease part
+     */
+    // leave our locks and intent to recovery as if we crashed, and go on with a new id, the old
+    // one is recovered once its lease expires
+    fn abandon(&mut self) {
+        if self.crashed {
+            return;
+        }
+        let id = self.mempool.read().unwrap().register_client();
+        self.mempool.read().unwrap().pin(id);
+        self.id = id;
+        self.lock_owner = id as u8;
+        self.holds_lease = false;
+    }
+
+    fn renew_lease(&mut self) {
+        self.mempool
+            .read()
+            .unwrap()
+            .renew_lease(self.lock_owner as usize);
+        self.holds_lease = true;
+    }
+
+    fn set_intent(&mut self, intent: LockIntent, index: usize, local_depth: u8, subtable: u64) {
+        self.renew_lease();
+        self.mempool.read().unwrap().set_lock_intent(
+            self.lock_owner as usize,
+            intent,
+            index,
+            local_depth,
+            subtable,
+        );
+    }
+
+    fn release_lease(&mut self) {
+        if self.crashed {
+            return;
+        }
+        self.mempool
+            .read()
+            .unwrap()
+            .release_lease(self.lock_owner as usize);
+        self.holds_lease = false;
+    }
+
+    // called while waiting for others, a crashed client never releases its locks
+    fn wait_for_others(&mut self) -> Result<(), RaceError> {
+        if self.holds_lease {
+            self.renew_lease();
+        }
+        let owners = self.mempool.read().unwrap().get_expired_owners();
+        for owner in owners {
+            if owner != self.id {
+                self.recover(owner)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn contains_key(&self, subtable: *const Subtable, key: &String) -> bool {
+        let hash_1 = Hash::hash(key, HashMethod::CombinedBucket1) as usize;
+        let hash_2 = Hash::hash(key, HashMethod::CombinedBucket2) as usize;
+        let mut entry = ClientEntry { data: 0 };
+        entry.set_subtable_pointer(subtable as u64);
+        match entry.get_combined_buckets(hash_1, hash_2) {
+            Some(cbs) => cbs.iter().any(|cb| cb.get_by_key(key).is_some()),
+            None => false,
+        }
+    }
+
+    // the owner may crash after copying an item but before clearing the source
+    fn remove_moved_items(
+        &mut self,
+        source: *const Subtable,
+        target: *const Subtable,
+        bucket_groups: std::ops::Range<usize>,
+    ) -> Result<(), RaceError> {
+        for bucket_group_index in bucket_groups {
+            for bucket_index in 0..CONFIG.bucket_num {
+                for slot_index in 0..CONFIG.slot_num {
+                    let slot_pos = SlotPos {
+                        subtable: source,
+                        bucket_group: bucket_group_index,
+                        bucket: bucket_index,
+                        header: 0,
+                        slot: slot_index,
+                    };
+                    let (data, kv_data_op) = self.read_from_slot_pos_with_crc_check(&slot_pos)?;
+                    let moved = match kv_data_op {
+                        Some(kv_data) if kv_data.klen != 0 => {
+                            self.contains_key(target, &kv_data.key)
+                        }
+                        _ => false,
+                    };
+                    if moved && self.retry_write_slot(&slot_pos, 0, data)? {
+                        self.mempool.read().unwrap().free_data(data);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // a displaced item shares its kv block with the copy, keep only one slot
+    fn remove_duplicate_slots(&mut self, subtable: *const Subtable) -> Result<(), RaceError> {
+        let mut seen = HashSet::new();
+        for bucket_group_index in 0..CONFIG.bucket_group_num {
+            for bucket_index in 0..CONFIG.bucket_num {
+                for slot_index in 0..CONFIG.slot_num {
+                    let slot_pos = SlotPos {
+                        subtable,
+                        bucket_group: bucket_group_index,
+                        bucket: bucket_index,
+                        header: 0,
+                        slot: slot_index,
+                    };
+                    let data = self.mempool.read().unwrap().read_slot(&slot_pos);
+                    if (Slot { data }).judge_empty() {
+                        continue;
+                    }
+                    if !seen.insert(data) {
+                        self.retry_write_slot(&slot_pos, 0, data)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn recover_split(
+        &mut self,
+        old_index: usize,
+        old_depth: u8,
+        new_pointer: u64,
+    ) -> Result<(), RaceError> {
+        let old_pointer = self.directory.get_entry(old_index).get_subtable_pointer();
+        if new_pointer == 0
+            || self
+                .mempool
+                .read()
+                .unwrap()
+                .read_subtable_header(old_pointer as *const Subtable)
+                .0
+                != old_depth
+        {
+            // nothing is published yet, or the split has been published entirely
+            return Ok(());
+        }
+
+        // the new subtable is ready for migration, finish publishing it
+        let new_index = RaceUtils::get_new_suffix_from_old(old_index as u64, old_depth) as usize;
+        self.set_local_entries_and_push(old_index as u64, old_pointer, old_depth + 1)?;
+        self.set_local_entries_and_push(new_index as u64, new_pointer, old_depth + 1)?;
+        self.mempool.read().unwrap().set_subtable_header(
+            old_index,
+            old_depth + 1,
+            old_index as u64,
+        );
+        self.mempool.read().unwrap().increase_directory_epoch();
+        Ok(())
+    }
+
+    fn recover_merge(
+        &mut self,
+        kept_index: usize,
+        local_depth: u8,
+        merged_pointer: u64,
+    ) -> Result<(), RaceError> {
+        if merged_pointer == 0
+            || self
+                .mempool
+                .read()
+                .unwrap()
+                .read_subtable_header(merged_pointer as *const Subtable)
+                .0
+                == local_depth
+        {
+            // no header is changed, roll back by unlocking only
+            return Ok(());
+        }
+
+        // the merged subtable has been retired, finish the merge
+        let merged_index =
+            RaceUtils::get_new_suffix_from_old(kept_index as u64, local_depth - 1) as usize;
+        let kept_pointer = self.directory.get_entry(kept_index).get_subtable_pointer();
+        self.mempool.read().unwrap().set_subtable_header(
+            kept_index,
+            local_depth - 1,
+            kept_index as u64,
+        );
+        if self
+            .directory
+            .get_entry(merged_index)
+            .get_subtable_pointer()
+            == merged_pointer
+        {
+            self.remove_moved_items(
+                merged_pointer as *const Subtable,
+                kept_pointer as *const Subtable,
+                0..CONFIG.bucket_group_num,
+            )?;
+            self.merge_items(kept_index, merged_index, &mut Vec::new())?;
+        }
+        // the entries are pushed one by one after the items are moved, finish the rest
+        if (0..self.get_size()).any(|index| {
+            self.directory.get_entry_const(index).get_subtable_pointer() == merged_pointer
+        }) {
+            self.set_local_entries_and_push(kept_index as u64, kept_pointer, local_depth - 1)?;
+            self.mempool.read().unwrap().increase_directory_epoch();
+        }
+        self.mempool
+            .read()
+            .unwrap()
+            .free_subtable(merged_pointer as *const Subtable);
+        Ok(())
+    }
+
+    fn recover_resize(&mut self, global_depth: u8) {
+        // the new global depth may be published without the version
+        if self.mempool.read().unwrap().get_global_depth() != global_depth {
+            self.mempool.read().unwrap().increase_directory_version();
+            self.mempool.read().unwrap().increase_directory_epoch();
+        }
+    }
+
+    fn recover_migration(
+        &mut self,
+        bucket_group: usize,
+        local_depth: u8,
+        new_pointer: u64,
+    ) -> Result<(), RaceError> {
+        let new_pointer = new_pointer as *const Subtable;
+        if self
+            .mempool
+            .read()
+            .unwrap()
+            .get_migration_state(new_pointer, bucket_group)
+            != MigrationState::Running as u8
+        {
+            return Ok(());
+        }
+        let new_index = self
+            .mempool
+            .read()
+            .unwrap()
+            .read_subtable_header(new_pointer)
+            .1;
+        let old_index = RaceUtils::restrict_suffix_to(new_index, local_depth - 1) as usize;
+        let old_pointer =
+            self.directory.get_entry(old_index).get_subtable_pointer() as *const Subtable;
+        self.remove_moved_items(old_pointer, new_pointer, bucket_group..bucket_group + 1)?;
+        self.mempool
+            .read()
+            .unwrap()
+            .reset_migration(new_pointer, bucket_group);
+        Ok(())
+    }
+
+    fn unlock_owner_entries(&mut self) -> Result<(), RaceError> {
+        let size = RaceUtils::depth_to_size(self.mempool.read().unwrap().get_global_depth());
+        for index in 0..CONFIG.max_entry_num {
+            let data = self.mempool.read().unwrap().read_entry(index);
+            if RaceUtils::get_lock_owner(data) != self.lock_owner {
+                continue;
+            }
+            if index < size {
+                self.mempool.read().unwrap().unlock_entry(index, data);
+            } else {
+                // entries out of the directory are being initialized or cleared
+                self.push_entry(index, data, 0)?;
+            }
+        }
+        Ok(())
+    }
+
+    // roll back or finish what a crashed client was doing, then release its locks
+    fn recover(&mut self, owner: usize) -> Result<(), RaceError> {
+        if !self.mempool.read().unwrap().try_claim_lease(owner) {
+            return Ok(());
+        }
+
+        // act as the crashed owner, so that its locked entries match
+        let directory = std::mem::replace(
+            &mut self.directory,
+            self.mempool.read().unwrap().get_directory(),
+        );
+        self.clear_all_lock_status();
+        let lock_owner = self.lock_owner;
+        let holds_lease = self.holds_lease;
+        self.lock_owner = owner as u8;
+
+        // on failure the claimed lease expires again, and the owner is recovered later
+        let result = self.recover_as_owner(owner);
+
+        self.lock_owner = lock_owner;
+        self.holds_lease = holds_lease;
+        self.directory = directory;
+        result
+    }
+
+    fn recover_as_owner(&mut self, owner: usize) -> Result<(), RaceError> {
+        let (intent, index, local_depth, subtable) =
+            self.mempool.read().unwrap().get_lock_intent(owner);
+        match intent {
+            x if x == LockIntent::Split as u8 => {
+                self.recover_split(index, local_depth, subtable)?
+            }
+            x if x == LockIntent::Merge as u8 => {
+                self.recover_merge(index, local_depth, subtable)?
+            }
+            x if x == LockIntent::Resize as u8 => self.recover_resize(local_depth),
+            x if x == LockIntent::Displace as u8 => {
+                self.remove_duplicate_slots(subtable as *const Subtable)?
+            }
+            x if x == LockIntent::Migrate as u8 => {
+                self.recover_migration(index, local_depth, subtable)?
+            }
+            _ => {}
+        }
+        self.unlock_owner_entries()?;
+        self.release_lease();
+        self.mempool.read().unwrap().unregister_client(owner);
+        Ok(())
+    }
+
+    /**
+     * Resize part
+     */
+    // split every subtable whose occupancy crosses the fill ratio, return the split number
+    pub fn resize_full_subtables(&mut self) -> Result<usize, RaceError> {
+        self.enter();
+        let result = self.resize_full_subtables_inner();
+        self.leave();
+        result
+    }
+
+    fn resize_full_subtables_inner(&mut self) -> Result<usize, RaceError> {
+        self.refresh_directory()?;
+        let mut split_num = 0;
+        let mut index = 0;
+        while index < self.get_size() && self.get_size() < CONFIG.max_entry_num {
+            let local_depth = self.directory.get_entry(index).get_local_depth();
+            if RaceUtils::restrict_suffix_to(index as u64, local_depth) as usize == index
+                && self.check_resize_occupancy(index)
+            {
+                self.rehash(index)?;
+                split_num += 1;
+            }
+            index += 1;
+        }
+        Ok(split_num)
+    }
+
+    // sampled, the resizer checks every subtable on each tick
+    fn check_resize_occupancy(&self, index: usize) -> bool {
+        let pointer = self.directory.get_entry_const(index).get_subtable_pointer();
+        let used_slot_num = self
+            .mempool
+            .read()
+            .unwrap()
+            .sample_subtable(pointer as *const Subtable);
+        used_slot_num * 100 >= Subtable::get_capacity() * CONFIG.resize_fill_percent
+    }
+
+    /**
+     * Shrink part
+     */
+    fn find_empty_slot(&self, subtable: *const Subtable, key: &String) -> Option<SlotPos> {
+        let hash_1 = Hash::hash(key, HashMethod::CombinedBucket1) as usize;
+        let hash_2 = Hash::hash(key, HashMethod::CombinedBucket2) as usize;
+        for (bucket_group, bucket) in [(hash_1, 0), (hash_1, 1), (hash_2, 2), (hash_2, 1)] {
+            for slot in 0..CONFIG.slot_num {
+                let slot_pos = SlotPos {
+                    subtable,
+                    bucket_group,
+                    bucket,
+                    header: 0,
+                    slot,
+                };
+                let data = self.mempool.read().unwrap().read_slot(&slot_pos);
+                if (Slot { data }).judge_empty() {
+                    return Some(slot_pos);
+                }
+            }
+        }
+        None
+    }
+
+    fn check_merge_placement(
+        &mut self,
+        kept_index: usize,
+        merged_index: usize,
+    ) -> Result<bool, RaceError> {
+        let kept = self.directory.get_entry(kept_index).get_subtable_pointer() as *const Subtable;
+        let merged = self
+            .directory
+            .get_entry(merged_index)
+            .get_subtable_pointer() as *const Subtable;
+
+        // count free slots of every bucket in the kept subtable
+        let mut free_slot_nums = HashMap::new();
+        for bucket_group_index in 0..CONFIG.bucket_group_num {
+            for bucket_index in 0..CONFIG.bucket_num {
+                let used_slot_num = unsafe {
+                    (*kept).bucket_groups[bucket_group_index].buckets[bucket_index]
+                        .get_non_empty_slot_num()
+                };
+                free_slot_nums.insert(
+                    (bucket_group_index, bucket_index),
+                    CONFIG.slot_num - used_slot_num,
+                );
+            }
+        }
+
+        // every item of the merged subtable must find a free slot in one of its buckets, in the
+        // order merge_items moves them
+        for bucket_group_index in 0..CONFIG.bucket_group_num {
+            for bucket_index in 0..CONFIG.bucket_num {
+                for slot_index in 0..CONFIG.slot_num {
+                    let slot_pos = SlotPos {
+                        subtable: merged,
+                        bucket_group: bucket_group_index,
+                        bucket: bucket_index,
+                        header: 0,
+                        slot: slot_index,
+                    };
+                    let (_, kv_data_op) = self.read_from_slot_pos_with_crc_check(&slot_pos)?;
+                    let candidates = match kv_data_op {
+                        Some(kv_data) if kv_data.klen != 0 => {
+                            let hash_1 =
+                                Hash::hash(&kv_data.key, HashMethod::CombinedBucket1) as usize;
+                            let hash_2 =
+                                Hash::hash(&kv_data.key, HashMethod::CombinedBucket2) as usize;
+                            vec![(hash_1, 0), (hash_1, 1), (hash_2, 2), (hash_2, 1)]
+                        }
+                        _ => continue,
+                    };
+                    let candidate = candidates
+                        .into_iter()
+                        .find(|candidate| free_slot_nums[candidate] > 0);
+                    match candidate {
+                        Some(candidate) => *free_slot_nums.get_mut(&candidate).unwrap() -= 1,
+                        None => return Ok(false),
+                    }
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    // moved items are recorded as (source, target), so that a failed merge can be rolled back
+    fn merge_items(
+        &mut self,
+        kept_index: usize,
+        merged_index: usize,
+        moved: &mut Vec<(SlotPos, SlotPos)>,
+    ) -> Result<(), RaceError> {
+        let kept = self.directory.get_entry(kept_index).get_subtable_pointer() as *const Subtable;
+        let merged = self
+            .directory
+            .get_entry(merged_index)
+            .get_subtable_pointer() as *const Subtable;
+        for bucket_group_index in 0..CONFIG.bucket_group_num {
+            // a long merge must not be taken for a crash
+            self.renew_lease();
+            for bucket_index in 0..CONFIG.bucket_num {
+                for slot_index in 0..CONFIG.slot_num {
+                    let slot_pos = SlotPos {
+                        subtable: merged,
+                        bucket_group: bucket_group_index,
+                        bucket: bucket_index,
+                        header: 0,
+                        slot: slot_index,
+                    };
+                    if let Some(target) = self.move_item(&slot_pos, kept, None, |_| true)? {
+                        moved.push((slot_pos, target));
+                    }
+                }
+            }
+            #[cfg(any(test, feature = "fault-injection"))]
+            self.crash_point(CrashPoint::DuringMerge)?;
+        }
+        Ok(())
+    }
+
+    fn merge(&mut self, index: usize) -> Result<bool, RaceError> {
+        let local_depth = self.directory.get_entry(index).get_local_depth();
+        if local_depth <= CONFIG.min_local_depth {
+            return Ok(false);
+        }
+
+        // the kept subtable owns the suffix without the top bit
+        let kept_index = RaceUtils::restrict_suffix_to(index as u64, local_depth - 1) as usize;
+        let merged_index =
+            RaceUtils::get_new_suffix_from_old(kept_index as u64, local_depth - 1) as usize;
+        if self.directory.get_entry(kept_index).get_local_depth() != local_depth
+            || self.directory.get_entry(merged_index).get_local_depth() != local_depth
+        {
+            // one of the siblings has been split further, they can not be merged
+            return Ok(false);
+        }
+
+        // check occupancy before taking any lock
+        if !self.check_merge_occupancy(kept_index, merged_index) {
+            return Ok(false);
+        }
+
+        // we must try lock and get newest local depth
+        self.lock_suffix_and_flush(kept_index, merged_index, local_depth)?;
+
+        // check again, someone may have changed the directory or inserted items
+        if self.directory.get_entry(kept_index).get_local_depth() != local_depth
+            || self.directory.get_entry(merged_index).get_local_depth() != local_depth
+        {
+            self.unlock_suffix_with_depth(kept_index as u64, local_depth);
+            self.unlock_suffix_with_depth(merged_index as u64, local_depth);
+            self.release_lease();
+            return Ok(false);
+        }
+
+        // the last split of this suffix pair must be finished before merging
+        let mergeable = self
+            .finish_migration(kept_index, local_depth)
+            .and_then(|_| {
+                Ok(self.check_merge_occupancy(kept_index, merged_index)
+                    && self.check_merge_placement(kept_index, merged_index)?)
+            });
+        if mergeable != Ok(true) {
+            self.unlock_suffix(kept_index as u64);
+            self.unlock_suffix(merged_index as u64);
+            self.release_lease();
+            return mergeable;
+        }
+
+        let kept_pointer = self.directory.get_entry(kept_index).get_subtable_pointer();
+        let merged_pointer = self
+            .directory
+            .get_entry(merged_index)
+            .get_subtable_pointer();
+
+        self.set_intent(LockIntent::Merge, kept_index, local_depth, merged_pointer);
+
+        // retire the merged subtable first, its suffix can never match a shorter depth,
+        // so that writers on it will refresh and redo
+        self.mempool.read().unwrap().set_subtable_header(
+            merged_index,
+            local_depth - 1,
+            merged_index as u64,
+        );
+        self.mempool.read().unwrap().set_subtable_header(
+            kept_index,
+            local_depth - 1,
+            kept_index as u64,
+        );
+        #[cfg(any(test, feature = "fault-injection"))]
+        self.crash_point(CrashPoint::AfterRetire)?;
+
+        // move items from merged subtable to kept subtable, the merged subtable is retired
+        // and can not be used again, a merge failing from here on is finished by recovery
+        let mut moved = Vec::new();
+        let result = match self.merge_items(kept_index, merged_index, &mut moved) {
+            // writers have filled the kept subtable since the placement was checked
+            Err(RaceError::NoFreeSlot) => {
+                self.roll_back_merge(kept_index, merged_index, local_depth, moved)
+            }
+            result => result.map(|_| true),
+        };
+        if result != Ok(true) {
+            if result.is_err() {
+                self.abandon();
+            }
+            return result;
+        }
+
+        // set entry, both suffixes point to kept subtable now
+        if let Err(e) =
+            self.set_local_entries_and_push(kept_index as u64, kept_pointer, local_depth - 1)
+        {
+            self.abandon();
+            return Err(e);
+        }
+        self.advance_epoch();
+
+        // free merged subtable, a recovery must not free it again
+        self.set_intent(LockIntent::None, kept_index, local_depth, 0);
+        self.mempool
+            .read()
+            .unwrap()
+            .free_subtable(merged_pointer as *const Subtable);
+
+        // unlock suffix, the kept suffix covers the merged one now
+        self.unlock_suffix(kept_index as u64);
+        self.release_lease();
+        Ok(true)
+    }
+
+    // move the items back to where they were and give the headers back, the merged header last,
+    // as a merge whose merged subtable is still retired is finished by recovery
+    fn roll_back_merge(
+        &mut self,
+        kept_index: usize,
+        merged_index: usize,
+        local_depth: u8,
+        moved: Vec<(SlotPos, SlotPos)>,
+    ) -> Result<bool, RaceError> {
+        let merged = self
+            .directory
+            .get_entry(merged_index)
+            .get_subtable_pointer() as *const Subtable;
+        for (source, target) in moved.into_iter().rev() {
+            // nobody writes into the retired subtable, so the slot it came from is still free
+            self.move_item(&target, merged, Some(&source), |hash| {
+                RaceUtils::restrict_suffix_to(hash, local_depth) == merged_index as u64
+            })?;
+        }
+        self.mempool.read().unwrap().set_subtable_header(
+            kept_index,
+            local_depth,
+            kept_index as u64,
+        );
+        self.mempool.read().unwrap().set_subtable_header(
+            merged_index,
+            local_depth,
+            merged_index as u64,
+        );
+        self.unlock_suffix(kept_index as u64);
+        self.unlock_suffix(merged_index as u64);
+        self.release_lease();
+        Ok(false)
+    }
+
+    fn check_merge_occupancy(&self, kept_index: usize, merged_index: usize) -> bool {
+        let kept_pointer = self
+            .directory
+            .get_entry_const(kept_index)
+            .get_subtable_pointer();
+        let merged_pointer = self
+            .directory
+            .get_entry_const(merged_index)
+            .get_subtable_pointer();
+        if kept_pointer == merged_pointer {
+            return false;
+        }
+        let used_slot_num = self
+            .mempool
+            .read()
+            .unwrap()
+            .count_subtable(kept_pointer as *const Subtable)
+            + self
+                .mempool
+                .read()
+                .unwrap()
+                .count_subtable(merged_pointer as *const Subtable);
+        used_slot_num * 100 < Subtable::get_capacity() * CONFIG.merge_threshold_percent
+    }
+
+    fn check_halvable(&self) -> bool {
+        let global_depth = self.directory.global_depth;
+        (0..self.get_size())
+            .all(|index| self.directory.get_entry_const(index).get_local_depth() < global_depth)
+    }
+
+    fn halve_size(&mut self) -> Result<bool, RaceError> {
+        if self.directory.global_depth <= CONFIG.min_local_depth || !self.check_halvable() {
+            return Ok(false);
+        }
+
+        // lock all
+        let old_size = self.get_size();
+        self.lock_all()?;
+        let new_size = self.get_size();
+        if old_size != new_size || !self.check_halvable() {
+            // shows someone has update the directory
+            self.unlock_all();
+            self.release_lease();
+            return Ok(false);
+        }
+        self.set_intent(LockIntent::Resize, 0, self.directory.global_depth, 0);
+
+        // begin halve size now!
+        // set global depth first, so that no one will use the upper half
+        self.directory.global_depth -= 1;
+        self.mempool.read().unwrap().decrease_global_depth();
+        self.directory.version += 1;
+        self.mempool.read().unwrap().increase_directory_version();
+        self.advance_epoch();
+
+        // clear the upper half, it will be initialized again by double size
+        for index in old_size / 2..old_size {
+            let locked_data = self
+                .directory
+                .get_entry(index)
+                .get_locked_data(self.lock_owner);
+            if let Err(e) = self.push_entry(index, locked_data, 0) {
+                // the global depth is published, recovery clears the rest
+                self.abandon();
+                return Err(e);
+            }
+            self.directory.get_entry(index).set_data(0);
+        }
+
+        // unlock the lower half
+        self.unlock_all();
+        self.release_lease();
+        Ok(true)
+    }
+
+    // merge sparse sibling subtables and halve the directory, return the merged number
+    pub fn shrink(&mut self) -> Result<usize, RaceError> {
+        self.enter();
+        let result = self.shrink_inner();
+        self.leave();
+        result
+    }
+
+    fn shrink_inner(&mut self) -> Result<usize, RaceError> {
+        self.refresh_directory()?;
+        let mut merged_num = 0;
+        let mut index = 0;
+        while index < self.get_size() {
+            if self.merge(index)? {
+                // the merged subtable may be merged again with a shorter depth
+                merged_num += 1;
+            } else {
+                index += 1;
+            }
+        }
+        while self.halve_size()? {}
+        Ok(merged_num)
+    }
+
+    // evict up to num items chosen by the eviction policy of the pool, return the evicted number
+    pub fn evict(&mut self, num: usize) -> Result<usize, RaceError> {
+        self.enter();
+        let result = self.check_mempool().and_then(|_| self.evict_inner(num));
+        self.leave();
+        result
+    }
+
+    fn evict_inner(&mut self, num: usize) -> Result<usize, RaceError> {
+        let policy = match self.mempool.read().unwrap().get_eviction_policy() {
+            Some(policy) => policy,
+            None => return Ok(0),
+        };
+        let victims = policy.choose_victims(&self.mempool.read().unwrap(), &self.directory, num);
+        Ok(self.evict_victims(victims))
+    }
+
+    fn evict_victims(&mut self, victims: Vec<(SlotPos, u64)>) -> usize {
+        let mut evicted_num = 0;
+        for (slot_pos, data) in victims {
+            if self.is_migrating_group(&slot_pos) {
+                continue;
+            }
+            // a victim changed meanwhile is spared, like a racing delete
+            if self.mempool.read().unwrap().write_slot(&slot_pos, 0, data) {
+                self.mempool.read().unwrap().free_data(data);
+                evicted_num += 1;
+            }
+        }
+        evicted_num
+    }
+
+    // a bucket group of the new subtable of an unfinished split holds copies its mover may still
+    // withdraw, nobody else takes them away
+    fn is_migrating_group(&self, slot_pos: &SlotPos) -> bool {
+        self.mempool
+            .read()
+            .unwrap()
+            .get_migration_state(slot_pos.subtable, slot_pos.bucket_group)
+            != MigrationState::Done as u8
+    }
+
+    // evict the num coldest items of the subtable of an entry, return the evicted number
+    pub fn evict_coldest(
+        &mut self,
+        index: usize,
+        num: usize,
+        hotness: Hotness,
+    ) -> Result<usize, RaceError> {
+        self.enter();
+        let result = self
+            .check_mempool()
+            .and_then(|_| self.refresh_directory())
+            .map(|_| self.evict_coldest_inner(index, num, hotness));
+        self.leave();
+        result
+    }
+
+    fn evict_coldest_inner(&mut self, index: usize, num: usize, hotness: Hotness) -> usize {
+        if index >= self.get_size() {
+            return 0;
+        }
+        let pointer = self.directory.get_entry_const(index).get_subtable_pointer();
+        let victims = eviction::get_coldest_slots(
+            &self.mempool.read().unwrap(),
+            pointer as *const Subtable,
+            num,
+            hotness,
+        );
+        self.evict_victims(victims)
+    }
+
+    // evict the coldest items of every subtable whose occupancy crosses the eviction fill ratio
+    // until it is below it, return the evicted number
+    pub fn evict_cold_subtables(&mut self, hotness: Hotness) -> Result<usize, RaceError> {
+        self.enter();
+        let result = self.evict_cold_subtables_inner(hotness);
+        self.leave();
+        result
+    }
+
+    fn evict_cold_subtables_inner(&mut self, hotness: Hotness) -> Result<usize, RaceError> {
+        self.check_mempool()?;
+        self.refresh_directory()?;
+        let limit = Subtable::get_capacity() * CONFIG.evict_fill_percent / 100;
+        let mut evicted_num = 0;
+        for index in 0..self.get_size() {
+            let entry = self.directory.get_entry_const(index);
+            if RaceUtils::restrict_suffix_to(index as u64, entry.get_local_depth()) as usize
+                != index
+            {
+                continue;
+            }
+            let used_slot_num = self
+                .mempool
+                .read()
+                .unwrap()
+                .count_subtable(entry.get_subtable_pointer() as *const Subtable);
+            if used_slot_num >= limit {
+                evicted_num += self.evict_coldest_inner(index, used_slot_num - limit + 1, hotness);
+            }
+        }
+        Ok(evicted_num)
+    }
+
+    // evict until a block of size fits the quota, without a policy the write is rejected
+    fn reserve_quota(&mut self, size: usize) -> Result<(), RaceError> {
+        while self.mempool.read().unwrap().is_over_quota(size) {
+            if self.evict_inner(CONFIG.evict_batch_num)? == 0 {
+                return Err(RaceError::QuotaExceeded);
+            }
+        }
+        Ok(())
+    }
+
+    // move kv blocks out of sparse chunks so that the chunks are released once their old blocks
+    // are reclaimed, return the moved number
+    pub fn compact(&mut self) -> Result<usize, RaceError> {
+        self.enter();
+        let memory_manager = self.mempool.read().unwrap().get_memory_manager().clone();
+        memory_manager.begin_compaction(CONFIG.compact_used_percent);
+        let result = self.compact_inner(&memory_manager);
+        memory_manager.end_compaction();
+        self.leave();
+        result
+    }
+
+    fn compact_inner(&mut self, memory_manager: &MemoryManager) -> Result<usize, RaceError> {
+        self.check_mempool()?;
+        self.refresh_directory()?;
+        let mut moved_num = 0;
+        let mut subtables = HashSet::new();
+        for index in 0..self.get_size() {
+            let subtable =
+                self.directory.get_entry(index).get_subtable_pointer() as *const Subtable;
+            if !subtables.insert(subtable) {
+                continue;
+            }
+            for bucket_group_index in 0..CONFIG.bucket_group_num {
+                for bucket_index in 0..CONFIG.bucket_num {
+                    for slot_index in 0..CONFIG.slot_num {
+                        let slot_pos = SlotPos {
+                            subtable,
+                            bucket_group: bucket_group_index,
+                            bucket: bucket_index,
+                            header: 0,
+                            slot: slot_index,
+                        };
+                        if self.compact_slot(&slot_pos, memory_manager)? {
+                            moved_num += 1;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(moved_num)
+    }
+
+    // the copy replaces the slot like an update of the same value, a writer or a migration
+    // changing the slot meanwhile wins and the copy is dropped; copies of a migration are left
+    // to their mover
+    fn compact_slot(
+        &mut self,
+        slot_pos: &SlotPos,
+        memory_manager: &MemoryManager,
+    ) -> Result<bool, RaceError> {
+        if self.is_migrating_group(slot_pos) {
+            return Ok(false);
+        }
+        let (data, kv_data) = match self.read_from_slot_pos_with_crc_check(slot_pos)? {
+            // an inline item has no block to move
+            (data, Some(kv_data)) if kv_data.klen != 0 && !(Slot { data }).is_inline() => {
+                (data, kv_data)
+            }
+            _ => return Ok(false),
+        };
+        let kv_block = (Slot { data }).get_kv_pointer();
+        let size = unsafe { (*kv_block).get_total_length() };
+        if !memory_manager.is_evacuating(kv_block as *const u8, size) {
+            return Ok(false);
+        }
+        let new_kv_block = self
+            .mempool
+            .read()
+            .unwrap()
+            .write_kv(kv_data.key.clone(), kv_data.value.clone());
+        if new_kv_block.is_null() {
+            return Err(RaceError::AllocationFailed);
+        }
+        unsafe { (*new_kv_block).copy_access(&*kv_block) };
+        let new_data = RaceUtils::set_data(&kv_data.key, &kv_data.value, new_kv_block as u64);
+        if self.mempool.read().unwrap().write_slot(slot_pos, new_data, data) {
+            self.mempool.read().unwrap().free_kv(kv_block, size);
+            return Ok(true);
+        }
+        self.mempool
+            .read()
+            .unwrap()
+            .free_kv(new_kv_block, unsafe { (*new_kv_block).get_total_length() });
+        Ok(false)
     }
 
     // only for test
@@ -806,8 +2585,8 @@ impl Client {
         &self.directory
     }
 
-    pub fn pub_rehash(&mut self, rehash_index: usize) {
-        self.rehash(rehash_index);
+    pub fn pub_rehash(&mut self, rehash_index: usize) -> Result<(), RaceError> {
+        self.rehash(rehash_index)
     }
 
     pub fn pub_get_size(&self) -> usize {
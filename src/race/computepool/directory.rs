@@ -2,6 +2,7 @@ use std::mem::size_of;
 
 use crate::{
     cfg::config::CONFIG,
+    numa::region::Regions,
     race::mempool::subtable::{self, CombinedBucket, Subtable},
 };
 
@@ -38,13 +39,14 @@ impl ClientEntry {
                     * (size_of::<u64>() - size_of::<u8>() - CONFIG.directory_lock_offset)))
     }
 
-    pub fn get_locked_data(&self) -> u64 {
+    pub fn get_locked_data(&self, owner: u8) -> u64 {
         (self.data
             & !(0xFF
                 << CONFIG.bits_of_byte
                     * (size_of::<u64>() - size_of::<u8>() - CONFIG.directory_lock_offset)))
-            | (1 << CONFIG.bits_of_byte
-                * (size_of::<u64>() - size_of::<u8>() - CONFIG.directory_lock_offset))
+            | ((owner as u64)
+                << (CONFIG.bits_of_byte
+                    * (size_of::<u64>() - size_of::<u8>() - CONFIG.directory_lock_offset)))
     }
 
     pub fn get_local_depth(&self) -> u8 {
@@ -55,14 +57,17 @@ impl ClientEntry {
     }
 
     pub fn get_subtable_pointer(&self) -> u64 {
-        (self.data
-            & !(0xFF
-                << CONFIG.bits_of_byte
-                    * (size_of::<u64>() - size_of::<u8>() - CONFIG.directory_lock_offset))
-            & !(0xFF
-                << CONFIG.bits_of_byte
-                    * (size_of::<u64>() - size_of::<u8>() - CONFIG.directory_localdepth_offset)))
-            as u64
+        Regions::to_local(
+            self.data
+                & !(0xFF
+                    << (CONFIG.bits_of_byte
+                        * (size_of::<u64>() - size_of::<u8>() - CONFIG.directory_lock_offset)))
+                & !(0xFF
+                    << (CONFIG.bits_of_byte
+                        * (size_of::<u64>()
+                            - size_of::<u8>()
+                            - CONFIG.directory_localdepth_offset))),
+        )
     }
 
     pub fn set_local_depth(&mut self, depth: u8) {
@@ -83,7 +88,7 @@ impl ClientEntry {
             & (0xFF
                 << CONFIG.bits_of_byte
                     * (size_of::<u64>() - size_of::<u8>() - CONFIG.directory_localdepth_offset)))
-            | subtable;
+            | Regions::to_remote(subtable);
     }
 
     pub fn get_combined_buckets(
@@ -117,6 +122,8 @@ impl ClientEntry {
 
 pub struct ClientDirectory {
     pub global_depth: u8,
+    pub version: u64,
+    pub epoch: u64,
     pub entries: [ClientEntry; CONFIG.max_entry_num],
 }
 
@@ -124,6 +131,8 @@ impl ClientDirectory {
     pub fn new() -> Self {
         ClientDirectory {
             global_depth: 0,
+            version: 0,
+            epoch: 0,
             entries: [ClientEntry { data: 0 }; CONFIG.max_entry_num],
         }
     }
@@ -0,0 +1,255 @@
+use super::directory::ClientDirectory;
+use crate::cfg::config::CONFIG;
+use crate::race::common::kvblock::KVBlockMem;
+use crate::race::common::utils::RaceUtils;
+use crate::race::mempool::mempool::MemPool;
+use crate::race::mempool::subtable::{Slot, SlotPos, Subtable};
+use std::cmp::Reverse;
+use std::sync::Mutex;
+
+// picks items to evict when the pool reaches its quota, the client deletes every victim whose
+// slot still holds the chosen data
+pub trait EvictionPolicy: Send + Sync {
+    // a search has found the item of the block
+    fn on_access(&self, _kv_block: *const KVBlockMem) {}
+
+    // up to num non-empty slots and their data
+    fn choose_victims(
+        &self,
+        mempool: &MemPool,
+        directory: &ClientDirectory,
+        num: usize,
+    ) -> Vec<(SlotPos, u64)>;
+}
+
+fn get_slot_pos(subtable: *const Subtable, position: usize) -> SlotPos {
+    SlotPos {
+        subtable,
+        bucket_group: position / (CONFIG.bucket_num * CONFIG.slot_num),
+        bucket: position / CONFIG.slot_num % CONFIG.bucket_num,
+        header: 0,
+        slot: position % CONFIG.slot_num,
+    }
+}
+
+fn get_slot_num() -> usize {
+    CONFIG.bucket_group_num * CONFIG.bucket_num * CONFIG.slot_num
+}
+
+// every victim is the first item from a random slot on
+pub struct RandomEviction {
+    seed: Mutex<u64>,
+}
+
+impl RandomEviction {
+    pub fn new(seed: u64) -> Self {
+        RandomEviction {
+            // xorshift never leaves zero
+            seed: Mutex::new(seed | 1),
+        }
+    }
+
+    fn next_random(&self) -> u64 {
+        let mut seed = self.seed.lock().unwrap();
+        *seed ^= *seed << 13;
+        *seed ^= *seed >> 7;
+        *seed ^= *seed << 17;
+        *seed
+    }
+}
+
+impl EvictionPolicy for RandomEviction {
+    fn choose_victims(
+        &self,
+        mempool: &MemPool,
+        directory: &ClientDirectory,
+        num: usize,
+    ) -> Vec<(SlotPos, u64)> {
+        let size = RaceUtils::depth_to_size(directory.global_depth);
+        let mut victims: Vec<(SlotPos, u64)> = Vec::new();
+        for _ in 0..num {
+            let mut index = self.next_random() as usize % size;
+            let mut position = self.next_random() as usize % get_slot_num();
+            for _ in 0..size * get_slot_num() {
+                let subtable =
+                    directory.get_entry_const(index).get_subtable_pointer() as *const Subtable;
+                let slot_pos = get_slot_pos(subtable, position);
+                let data = mempool.read_slot(&slot_pos);
+                if !(Slot { data }).judge_empty()
+                    && victims.iter().all(|(_, victim)| *victim != data)
+                {
+                    victims.push((slot_pos, data));
+                    break;
+                }
+                position += 1;
+                if position == get_slot_num() {
+                    position = 0;
+                    index = (index + 1) % size;
+                }
+            }
+        }
+        victims
+    }
+}
+
+// a hand sweeps the slots of every subtable, a referenced item is spared once and loses its bit
+pub struct ClockEviction {
+    // entry index and slot position in its subtable
+    hand: Mutex<(usize, usize)>,
+}
+
+impl ClockEviction {
+    pub fn new() -> Self {
+        ClockEviction {
+            hand: Mutex::new((0, 0)),
+        }
+    }
+}
+
+impl EvictionPolicy for ClockEviction {
+    fn on_access(&self, kv_block: *const KVBlockMem) {
+        unsafe { (*kv_block).set_referenced() };
+    }
+
+    fn choose_victims(
+        &self,
+        mempool: &MemPool,
+        directory: &ClientDirectory,
+        num: usize,
+    ) -> Vec<(SlotPos, u64)> {
+        let size = RaceUtils::depth_to_size(directory.global_depth);
+        let mut hand = self.hand.lock().unwrap();
+        let mut victims = Vec::new();
+        // two rounds clear every bit and then find any item left
+        let mut step_num = 0;
+        while victims.len() < num && step_num < 2 * size * get_slot_num() {
+            let (index, position) = *hand;
+            if index >= size {
+                // the directory has been halved
+                *hand = (0, 0);
+                continue;
+            }
+            *hand = if position + 1 == get_slot_num() {
+                ((index + 1) % size, 0)
+            } else {
+                (index, position + 1)
+            };
+            step_num += 1;
+            let entry = directory.get_entry_const(index);
+            // a subtable is swept from its first entry only
+            if RaceUtils::restrict_suffix_to(index as u64, entry.get_local_depth()) as usize
+                != index
+            {
+                *hand = ((index + 1) % size, 0);
+                continue;
+            }
+            let slot_pos = get_slot_pos(entry.get_subtable_pointer() as *const Subtable, position);
+            let data = mempool.read_slot(&slot_pos);
+            let slot = Slot { data };
+            // an inline item has no block to be referenced
+            if slot.judge_empty()
+                || (!slot.is_inline() && unsafe { (*slot.get_kv_pointer()).clear_referenced() })
+            {
+                continue;
+            }
+            victims.push((slot_pos, data));
+        }
+        victims
+    }
+}
+
+// what makes an item cold, the last access for an approximate lru and the access number for an
+// approximate lfu, ties of the access number go to the older item
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hotness {
+    Recency,
+    Frequency,
+}
+
+// up to num non-empty slots of a subtable from the coldest one, by the access metadata of their
+// blocks
+pub fn get_coldest_slots(
+    mempool: &MemPool,
+    subtable: *const Subtable,
+    num: usize,
+    hotness: Hotness,
+) -> Vec<(SlotPos, u64)> {
+    let tick = mempool.get_access_tick();
+    let mut slots = Vec::new();
+    for position in 0..get_slot_num() {
+        let slot_pos = get_slot_pos(subtable, position);
+        let data = mempool.read_slot(&slot_pos);
+        let slot = Slot { data };
+        if slot.judge_empty() {
+            continue;
+        }
+        // an inline item has no block to keep its hotness, it is taken as never accessed
+        let (last_access, access_num) = if slot.is_inline() {
+            (0, 0)
+        } else {
+            let kv_block = unsafe { &*slot.get_kv_pointer() };
+            (kv_block.get_last_access(), kv_block.get_access_num())
+        };
+        let age = tick.wrapping_sub(last_access);
+        let coldness = match hotness {
+            Hotness::Recency => (0, age),
+            Hotness::Frequency => (u32::MAX - access_num, age),
+        };
+        slots.push((coldness, slot_pos, data));
+    }
+    slots.sort_by_key(|(coldness, _, _)| Reverse(*coldness));
+    slots
+        .into_iter()
+        .take(num)
+        .map(|(_, slot_pos, data)| (slot_pos, data))
+        .collect()
+}
+
+// the coldest items of one subtable after another, searches only keep the hotness when the pool
+// tracks accesses
+pub struct ColdestEviction {
+    hotness: Hotness,
+    // entry index of the next subtable
+    next: Mutex<usize>,
+}
+
+impl ColdestEviction {
+    pub fn new(hotness: Hotness) -> Self {
+        ColdestEviction {
+            hotness,
+            next: Mutex::new(0),
+        }
+    }
+}
+
+impl EvictionPolicy for ColdestEviction {
+    fn choose_victims(
+        &self,
+        mempool: &MemPool,
+        directory: &ClientDirectory,
+        num: usize,
+    ) -> Vec<(SlotPos, u64)> {
+        let size = RaceUtils::depth_to_size(directory.global_depth);
+        let mut next = self.next.lock().unwrap();
+        for _ in 0..size {
+            let index = *next % size;
+            *next = (index + 1) % size;
+            let entry = directory.get_entry_const(index);
+            if RaceUtils::restrict_suffix_to(index as u64, entry.get_local_depth()) as usize
+                != index
+            {
+                continue;
+            }
+            let victims = get_coldest_slots(
+                mempool,
+                entry.get_subtable_pointer() as *const Subtable,
+                num,
+                self.hotness,
+            );
+            if !victims.is_empty() {
+                return victims;
+            }
+        }
+        Vec::new()
+    }
+}
@@ -1,2 +1,8 @@
 pub mod client;
 pub mod directory;
+pub mod eviction;
+pub mod replicated;
+pub mod resizer;
+pub mod sharded;
+#[cfg(any(test, feature = "fault-injection"))]
+pub mod simulator;
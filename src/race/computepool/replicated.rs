@@ -0,0 +1,291 @@
+use super::client::Client;
+use crate::cfg::config::CONFIG;
+use crate::race::common::error::RaceError;
+use crate::race::common::hash::{Hash, HashMethod};
+use crate::race::common::options::OpOptions;
+use crate::race::mempool::mempool::MemPool;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+// the pools of a replicated table and what all of its clients must agree on
+pub struct ReplicaSet {
+    mempools: Vec<Arc<RwLock<MemPool>>>,
+    primary: AtomicUsize,
+    // a replica that has missed writes serves nothing until repaired
+    stale: Vec<AtomicBool>,
+    // a write holds the lock of its key on the primary and all backups, so that backups apply
+    // the writes of a key in the order of the primary
+    key_locks: Vec<Mutex<()>>,
+    // writes share it, a repair takes it alone
+    repair_lock: RwLock<()>,
+}
+
+impl ReplicaSet {
+    // the first pool is the primary, the others are backups
+    pub fn new(mempools: Vec<Arc<RwLock<MemPool>>>) -> Self {
+        ReplicaSet {
+            stale: mempools.iter().map(|_| AtomicBool::new(false)).collect(),
+            mempools,
+            primary: AtomicUsize::new(0),
+            key_locks: (0..CONFIG.replica_key_lock_num)
+                .map(|_| Mutex::new(()))
+                .collect(),
+            repair_lock: RwLock::new(()),
+        }
+    }
+
+    pub fn get_primary(&self) -> usize {
+        self.primary.load(Ordering::SeqCst)
+    }
+
+    pub fn get_replica_num(&self) -> usize {
+        self.mempools.len()
+    }
+
+    pub fn is_stale(&self, replica: usize) -> bool {
+        self.stale[replica].load(Ordering::SeqCst)
+    }
+
+    fn set_stale(&self, replica: usize, stale: bool) {
+        self.stale[replica].store(stale, Ordering::SeqCst);
+    }
+
+    pub fn get_mempool(&self, replica: usize) -> &Arc<RwLock<MemPool>> {
+        &self.mempools[replica]
+    }
+
+    fn is_healthy(&self, replica: usize) -> bool {
+        !self.is_stale(replica) && !self.mempools[replica].read().unwrap().is_failed()
+    }
+
+    // promote the first healthy backup, the failed primary misses writes from now on, another
+    // client may have promoted one already
+    fn fail_over(&self, failed: usize) -> Result<(), RaceError> {
+        self.set_stale(failed, true);
+        match (0..self.mempools.len()).find(|replica| self.is_healthy(*replica)) {
+            Some(replica) => {
+                let _ = self.primary.compare_exchange(
+                    failed,
+                    replica,
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                );
+                Ok(())
+            }
+            None => Err(RaceError::PoolUnavailable),
+        }
+    }
+
+    fn get_key_lock(&self, key: &String) -> &Mutex<()> {
+        let hash = Hash::hash(key, HashMethod::Directory) as usize;
+        &self.key_locks[hash % self.key_locks.len()]
+    }
+}
+
+// primary-backup replication, every write allocates its kv block and writes its slot in the
+// primary first, which decides the result, and then in every backup
+pub struct ReplicatedClient {
+    replicas: Arc<ReplicaSet>,
+    // one client for each pool
+    clients: Vec<Client>,
+}
+
+// insert or overwrite, a backup may disagree with the primary about whether the key exists
+fn put(client: &mut Client, key: &String, val: &String) -> Result<(), RaceError> {
+    if !client.insert(key, val)? {
+        client.update(key, val)?;
+    }
+    Ok(())
+}
+
+impl ReplicatedClient {
+    pub fn new(replicas: Arc<ReplicaSet>) -> Self {
+        ReplicatedClient {
+            clients: replicas
+                .mempools
+                .iter()
+                .map(|mempool| Client::new(mempool.clone()))
+                .collect(),
+            replicas,
+        }
+    }
+
+    pub fn get_replicas(&self) -> &Arc<ReplicaSet> {
+        &self.replicas
+    }
+
+    pub fn get_primary(&self) -> usize {
+        self.replicas.get_primary()
+    }
+
+    pub fn get_replica_num(&self) -> usize {
+        self.replicas.get_replica_num()
+    }
+
+    pub fn is_stale(&self, replica: usize) -> bool {
+        self.replicas.is_stale(replica)
+    }
+
+    pub fn get_mempool(&self, replica: usize) -> &Arc<RwLock<MemPool>> {
+        self.replicas.get_mempool(replica)
+    }
+
+    pub fn get_client(&mut self, replica: usize) -> &mut Client {
+        &mut self.clients[replica]
+    }
+
+    fn on_primary<T>(
+        &mut self,
+        mut op: impl FnMut(&mut Client) -> Result<T, RaceError>,
+    ) -> Result<T, RaceError> {
+        loop {
+            let primary = self.replicas.get_primary();
+            match op(&mut self.clients[primary]) {
+                Err(RaceError::PoolUnavailable) => self.replicas.fail_over(primary)?,
+                result => return result,
+            }
+        }
+    }
+
+    // a backup failing to apply the write is left for repair
+    fn on_backups(&mut self, mut op: impl FnMut(&mut Client) -> Result<(), RaceError>) {
+        let primary = self.replicas.get_primary();
+        for replica in 0..self.clients.len() {
+            if replica == primary || self.replicas.is_stale(replica) {
+                continue;
+            }
+            if op(&mut self.clients[replica]).is_err() {
+                self.replicas.set_stale(replica, true);
+            }
+        }
+    }
+
+    // run a write on the primary, and on the backups if the primary has changed the key
+    fn replicate(
+        &mut self,
+        key: &String,
+        primary_op: impl FnMut(&mut Client) -> Result<bool, RaceError>,
+        backup_op: impl FnMut(&mut Client) -> Result<(), RaceError>,
+    ) -> Result<bool, RaceError> {
+        let replicas = self.replicas.clone();
+        let _repair_guard = replicas.repair_lock.read().unwrap();
+        let _key_guard = replicas.get_key_lock(key).lock().unwrap();
+        let changed = self.on_primary(primary_op)?;
+        if changed {
+            self.on_backups(backup_op);
+        }
+        Ok(changed)
+    }
+
+    pub fn search(&mut self, key: &String) -> Result<Option<String>, RaceError> {
+        self.search_with_options(key, &OpOptions::default())
+    }
+
+    pub fn search_with_options(
+        &mut self,
+        key: &String,
+        options: &OpOptions,
+    ) -> Result<Option<String>, RaceError> {
+        self.on_primary(|client| client.search_with_options(key, options))
+    }
+
+    pub fn insert(&mut self, key: &String, val: &String) -> Result<bool, RaceError> {
+        self.insert_with_options(key, val, &OpOptions::default())
+    }
+
+    // options limit the primary only, once the primary has decided backups must follow
+    pub fn insert_with_options(
+        &mut self,
+        key: &String,
+        val: &String,
+        options: &OpOptions,
+    ) -> Result<bool, RaceError> {
+        self.replicate(
+            key,
+            |client| client.insert_with_options(key, val, options),
+            |client| put(client, key, val),
+        )
+    }
+
+    pub fn delete(&mut self, key: &String) -> Result<bool, RaceError> {
+        self.delete_with_options(key, &OpOptions::default())
+    }
+
+    pub fn delete_with_options(
+        &mut self,
+        key: &String,
+        options: &OpOptions,
+    ) -> Result<bool, RaceError> {
+        self.replicate(
+            key,
+            |client| client.delete_with_options(key, options),
+            |client| client.delete(key).map(|_| ()),
+        )
+    }
+
+    pub fn update(&mut self, key: &String, val: &String) -> Result<bool, RaceError> {
+        self.update_with_options(key, val, &OpOptions::default())
+    }
+
+    pub fn update_with_options(
+        &mut self,
+        key: &String,
+        val: &String,
+        options: &OpOptions,
+    ) -> Result<bool, RaceError> {
+        self.replicate(
+            key,
+            |client| client.update_with_options(key, val, options),
+            |client| put(client, key, val),
+        )
+    }
+
+    // reconcile stale replicas reachable again with the primary, return the fixed item number,
+    // writes of all clients wait until the repair is done
+    pub fn repair(&mut self) -> Result<usize, RaceError> {
+        let replicas = self.replicas.clone();
+        let _repair_guard = replicas.repair_lock.write().unwrap();
+        let mut fixed_num = 0;
+        for replica in 0..self.clients.len() {
+            if replica == replicas.get_primary()
+                || !replicas.is_stale(replica)
+                || replicas.get_mempool(replica).read().unwrap().is_failed()
+            {
+                continue;
+            }
+            fixed_num += self.reconcile(replica)?;
+            replicas.set_stale(replica, false);
+        }
+        Ok(fixed_num)
+    }
+
+    fn reconcile(&mut self, replica: usize) -> Result<usize, RaceError> {
+        let keys: HashSet<String> = self
+            .on_primary(|client| client.scan_keys())?
+            .into_iter()
+            .collect();
+        let mut fixed_num = 0;
+        for key in keys.iter() {
+            let value = match self.on_primary(|client| client.search(key))? {
+                Some(value) => value,
+                None => continue,
+            };
+            let backup = &mut self.clients[replica];
+            if backup.search(key)?.as_ref() != Some(&value) {
+                put(backup, key, &value)?;
+                fixed_num += 1;
+            }
+        }
+
+        // keys deleted while the replica was away
+        let backup = &mut self.clients[replica];
+        for key in backup.scan_keys()? {
+            if !keys.contains(&key) {
+                backup.delete(&key)?;
+                fixed_num += 1;
+            }
+        }
+        Ok(fixed_num)
+    }
+}
@@ -0,0 +1,48 @@
+use super::client::Client;
+use crate::cfg::config::CONFIG;
+use crate::race::mempool::mempool::MemPool;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// A background thread splitting subtables before foreground inserts find them full
+pub struct Resizer {
+    running: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Resizer {
+    pub fn start(mempool: Arc<RwLock<MemPool>>) -> Self {
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = running.clone();
+        let handle = thread::Builder::new()
+            .name(String::from("race-resizer"))
+            .stack_size(CONFIG.thread_stack_size)
+            .spawn(move || {
+                let mut client = Client::new(mempool);
+                while thread_running.load(Ordering::SeqCst) {
+                    let _ = client.resize_full_subtables();
+                    thread::sleep(Duration::from_millis(CONFIG.resize_interval_ms));
+                }
+            })
+            .unwrap();
+        Resizer {
+            running,
+            handle: Some(handle),
+        }
+    }
+
+    pub fn stop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            handle.join().unwrap();
+        }
+    }
+}
+
+impl Drop for Resizer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
@@ -0,0 +1,194 @@
+use super::client::Client;
+use crate::cfg::config::CONFIG;
+use crate::race::common::error::RaceError;
+use crate::race::common::options::OpOptions;
+use crate::race::mempool::mempool::MemPool;
+use crc::{Crc, CRC_64_REDIS};
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+
+// consistent-hash ring, each shard owns the keys before its virtual nodes
+pub struct HashRing {
+    nodes: BTreeMap<u64, usize>,
+}
+
+impl HashRing {
+    pub fn new() -> Self {
+        HashRing {
+            nodes: BTreeMap::new(),
+        }
+    }
+
+    fn hash(bytes: &[u8]) -> u64 {
+        Crc::<u64>::new(&CRC_64_REDIS).checksum(bytes)
+    }
+
+    fn get_node_position(shard: usize, virtual_node: usize) -> u64 {
+        HashRing::hash(format!("shard-{}-{}", shard, virtual_node).as_bytes())
+    }
+
+    pub fn add_shard(&mut self, shard: usize) {
+        for virtual_node in 0..CONFIG.shard_virtual_node_num {
+            self.nodes
+                .insert(HashRing::get_node_position(shard, virtual_node), shard);
+        }
+    }
+
+    pub fn remove_shard(&mut self, shard: usize) {
+        self.nodes.retain(|_, owner| *owner != shard);
+    }
+
+    pub fn get_shard(&self, key: &String) -> usize {
+        let position = HashRing::hash(key.as_bytes());
+        match self.nodes.range(position..).next() {
+            Some((_, shard)) => *shard,
+            // wrap around the ring
+            None => *self.nodes.values().next().expect("empty hash ring"),
+        }
+    }
+}
+
+struct Shard {
+    mempool: Arc<RwLock<MemPool>>,
+    client: Client,
+}
+
+// routes every key to one of several memory pools, each pool keeps its own directory and
+// resizes independently
+pub struct ShardedClient {
+    shards: Vec<Shard>,
+    ring: HashRing,
+}
+
+impl ShardedClient {
+    // clients built from the same pools in the same order route keys the same way
+    pub fn new(mempools: Vec<Arc<RwLock<MemPool>>>) -> Self {
+        let mut sharded_client = ShardedClient {
+            shards: Vec::new(),
+            ring: HashRing::new(),
+        };
+        for mempool in mempools {
+            sharded_client.push_shard(mempool);
+        }
+        sharded_client
+    }
+
+    fn push_shard(&mut self, mempool: Arc<RwLock<MemPool>>) -> usize {
+        let shard = self.shards.len();
+        self.shards.push(Shard {
+            client: Client::new(mempool.clone()),
+            mempool,
+        });
+        self.ring.add_shard(shard);
+        shard
+    }
+
+    pub fn get_shard_num(&self) -> usize {
+        self.shards.len()
+    }
+
+    pub fn get_shard(&self, key: &String) -> usize {
+        self.ring.get_shard(key)
+    }
+
+    pub fn get_mempool(&self, shard: usize) -> &Arc<RwLock<MemPool>> {
+        &self.shards[shard].mempool
+    }
+
+    pub fn get_client(&mut self, shard: usize) -> &mut Client {
+        &mut self.shards[shard].client
+    }
+
+    fn get_client_for_key(&mut self, key: &String) -> &mut Client {
+        let shard = self.ring.get_shard(key);
+        &mut self.shards[shard].client
+    }
+
+    pub fn search(&mut self, key: &String) -> Result<Option<String>, RaceError> {
+        self.get_client_for_key(key).search(key)
+    }
+
+    pub fn search_with_options(
+        &mut self,
+        key: &String,
+        options: &OpOptions,
+    ) -> Result<Option<String>, RaceError> {
+        self.get_client_for_key(key)
+            .search_with_options(key, options)
+    }
+
+    pub fn insert(&mut self, key: &String, val: &String) -> Result<bool, RaceError> {
+        self.get_client_for_key(key).insert(key, val)
+    }
+
+    pub fn insert_with_options(
+        &mut self,
+        key: &String,
+        val: &String,
+        options: &OpOptions,
+    ) -> Result<bool, RaceError> {
+        self.get_client_for_key(key)
+            .insert_with_options(key, val, options)
+    }
+
+    pub fn delete(&mut self, key: &String) -> Result<bool, RaceError> {
+        self.get_client_for_key(key).delete(key)
+    }
+
+    pub fn delete_with_options(
+        &mut self,
+        key: &String,
+        options: &OpOptions,
+    ) -> Result<bool, RaceError> {
+        self.get_client_for_key(key)
+            .delete_with_options(key, options)
+    }
+
+    pub fn update(&mut self, key: &String, val: &String) -> Result<bool, RaceError> {
+        self.get_client_for_key(key).update(key, val)
+    }
+
+    pub fn update_with_options(
+        &mut self,
+        key: &String,
+        val: &String,
+        options: &OpOptions,
+    ) -> Result<bool, RaceError> {
+        self.get_client_for_key(key)
+            .update_with_options(key, val, options)
+    }
+
+    // add a shard and move the key ranges it takes over from others, return the moved number,
+    // other sharded clients must be rebuilt with the new pool list afterwards
+    pub fn add_shard(&mut self, mempool: Arc<RwLock<MemPool>>) -> Result<usize, RaceError> {
+        let new_shard = self.push_shard(mempool);
+        let mut moved_num = 0;
+        for shard in 0..new_shard {
+            moved_num += self.rebalance_shard(shard)?;
+        }
+        Ok(moved_num)
+    }
+
+    // move keys no longer owned by the shard, the copy is written before the source is removed
+    pub fn rebalance_shard(&mut self, shard: usize) -> Result<usize, RaceError> {
+        let keys = self.shards[shard].client.scan_keys()?;
+        let mut moved_num = 0;
+        for key in keys {
+            let owner = self.ring.get_shard(&key);
+            if owner == shard {
+                continue;
+            }
+            let value = match self.shards[shard].client.search(&key)? {
+                Some(value) => value,
+                // deleted since the scan
+                None => continue,
+            };
+            if !self.shards[owner].client.insert(&key, &value)? {
+                self.shards[owner].client.update(&key, &value)?;
+            }
+            self.shards[shard].client.delete(&key)?;
+            moved_num += 1;
+        }
+        Ok(moved_num)
+    }
+}
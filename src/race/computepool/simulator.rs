@@ -0,0 +1,354 @@
+use super::client::Client;
+use crate::cfg::config::CONFIG;
+use crate::race::common::hash::{Hash, HashMethod};
+use crate::race::common::utils::RaceUtils;
+use crate::race::mempool::mempool::MemPool;
+use crate::race::mempool::subtable::{SlotPos, Subtable};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops::Range;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+// a workload run by several clients against one pool, and the invariants it must keep
+pub trait Scenario: Sync {
+    fn get_thread_num(&self) -> usize;
+
+    // runs alone before the threads start
+    fn setup(&self, client: &mut Client);
+
+    fn run(&self, thread: usize, client: &mut Client);
+
+    // runs alone after every thread has finished
+    fn check(&self, mempool: &Arc<RwLock<MemPool>>, client: &mut Client) -> Result<(), String>;
+}
+
+pub struct Outcome {
+    // the thread chosen at every step, passing it as the schedule repeats the run
+    pub trace: Vec<usize>,
+    pub error: Option<String>,
+}
+
+// a schedule breaking the invariants of a scenario
+pub struct Failure {
+    pub seed: u64,
+    pub schedule: Vec<usize>,
+    pub error: String,
+}
+
+// steps of one thread are folded, "0x3 1x2" runs thread 0 for 3 steps and then thread 1 for 2
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "seed {}, {} steps:", self.seed, self.schedule.len())?;
+        let mut index = 0;
+        while index < self.schedule.len() {
+            let mut end = index;
+            while end < self.schedule.len() && self.schedule[end] == self.schedule[index] {
+                end += 1;
+            }
+            write!(f, " {}x{}", self.schedule[index], end - index)?;
+            index = end;
+        }
+        write!(f, "\n{}", self.error)
+    }
+}
+
+pub struct Simulator<S: Scenario> {
+    scenario: S,
+}
+
+impl<S: Scenario> Simulator<S> {
+    pub fn new(scenario: S) -> Self {
+        Simulator { scenario }
+    }
+
+    // every run starts from a fresh pool, so that only the schedule decides the result
+    pub fn run(&self, schedule: Vec<usize>, seed: Option<u64>) -> Outcome {
+        let mempool = Arc::new(RwLock::new(MemPool::new()));
+        let mut client = Client::new(mempool.clone());
+        self.scenario.setup(&mut client);
+        let thread_num = self.scenario.get_thread_num();
+        // clients are created in order, so that their ids do not depend on the os
+        let clients: Vec<Client> = (0..thread_num)
+            .map(|_| Client::new(mempool.clone()))
+            .collect();
+        let mut errors = Vec::new();
+        mempool
+            .read()
+            .unwrap()
+            .get_scheduler()
+            .start(thread_num, schedule, seed);
+        thread::scope(|scope| {
+            let handles: Vec<_> = clients
+                .into_iter()
+                .enumerate()
+                .map(|(thread, mut thread_client)| {
+                    let mempool = mempool.clone();
+                    let scenario = &self.scenario;
+                    thread::Builder::new()
+                        .stack_size(CONFIG.thread_stack_size)
+                        .spawn_scoped(scope, move || {
+                            let pool = mempool.read().unwrap();
+                            let scheduler = pool.get_scheduler();
+                            scheduler.enter_thread(thread);
+                            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                                scenario.run(thread, &mut thread_client)
+                            }));
+                            scheduler.exit_thread(thread);
+                            result
+                        })
+                        .unwrap()
+                })
+                .collect();
+            for (thread, handle) in handles.into_iter().enumerate() {
+                if let Ok(Err(e)) = handle.join() {
+                    let message = e
+                        .downcast_ref::<String>()
+                        .cloned()
+                        .or_else(|| e.downcast_ref::<&str>().map(|s| s.to_string()))
+                        .unwrap_or_default();
+                    errors.push(format!("thread {} panicked: {}", thread, message));
+                }
+            }
+        });
+        let (trace, exceeded) = mempool.read().unwrap().get_scheduler().stop();
+        if exceeded {
+            errors.push(format!("no progress in {} steps", CONFIG.sim_max_steps));
+        }
+        if errors.is_empty() {
+            if let Err(e) = self.scenario.check(&mempool, &mut client) {
+                errors.push(e);
+            }
+        }
+        Outcome {
+            trace,
+            error: if errors.is_empty() {
+                None
+            } else {
+                Some(errors.join("\n"))
+            },
+        }
+    }
+
+    // a random schedule for every seed, the first failure is shrunk
+    pub fn explore(&self, seeds: Range<u64>) -> Option<Failure> {
+        for seed in seeds {
+            let outcome = self.run(Vec::new(), Some(seed));
+            if let Some(error) = outcome.error {
+                let (schedule, error) = self.shrink(outcome.trace, error);
+                return Some(Failure {
+                    seed,
+                    schedule,
+                    error,
+                });
+            }
+        }
+        None
+    }
+
+    // a shorter schedule still failing, past its end the running thread goes on until it waits,
+    // so that dropping steps keeps the threads running in big pieces
+    pub fn shrink(&self, trace: Vec<usize>, error: String) -> (Vec<usize>, String) {
+        let mut runs = 0;
+        let mut schedule = trace;
+        let mut error = error;
+
+        // the shortest failing prefix
+        let (mut low, mut high) = (0, schedule.len());
+        while low < high && runs < CONFIG.sim_max_shrink_runs {
+            let middle = (low + high) / 2;
+            runs += 1;
+            match self.run(schedule[..middle].to_vec(), None).error {
+                Some(e) => {
+                    high = middle;
+                    error = e;
+                }
+                None => low = middle + 1,
+            }
+        }
+        schedule.truncate(high);
+
+        // then drop chunks of steps, halving the chunk when none can be dropped
+        let mut chunk = schedule.len() / 2;
+        while chunk > 0 && runs < CONFIG.sim_max_shrink_runs {
+            let mut start = 0;
+            let mut dropped = false;
+            while start < schedule.len() && runs < CONFIG.sim_max_shrink_runs {
+                let end = (start + chunk).min(schedule.len());
+                let mut candidate = schedule[..start].to_vec();
+                candidate.extend_from_slice(&schedule[end..]);
+                runs += 1;
+                match self.run(candidate.clone(), None).error {
+                    Some(e) => {
+                        schedule = candidate;
+                        error = e;
+                        dropped = true;
+                    }
+                    None => start = end,
+                }
+            }
+            if !dropped {
+                chunk /= 2;
+            }
+        }
+        (schedule, error)
+    }
+}
+
+// every key of expected (none for deleted) is found exactly once with its value, nothing else is
+// stored, and every bucket carries the local depth and suffix of its directory entries
+pub fn check_table(
+    mempool: &Arc<RwLock<MemPool>>,
+    expected: &HashMap<String, Option<String>>,
+) -> Result<(), String> {
+    let directory = mempool.read().unwrap().get_directory();
+    let size = RaceUtils::depth_to_size(directory.global_depth);
+    let mut found: HashMap<String, (usize, String)> = HashMap::new();
+    let mut subtables = HashSet::new();
+    for index in 0..size {
+        let entry = directory.get_entry_const(index);
+        let subtable = entry.get_subtable_pointer() as *const Subtable;
+        let local_depth = entry.get_local_depth();
+        let suffix = RaceUtils::restrict_suffix_to(index as u64, local_depth);
+        for bucket_group in 0..CONFIG.bucket_group_num {
+            for bucket in 0..CONFIG.bucket_num {
+                let header = unsafe { (*subtable).get_bucket_header_atomic(bucket_group, bucket) };
+                if header.get_local_depth() != local_depth || header.get_suffix() != suffix {
+                    return Err(format!(
+                        "entry {} has depth {} suffix {}, bucket {}:{} has depth {} suffix {}",
+                        index,
+                        local_depth,
+                        suffix,
+                        bucket_group,
+                        bucket,
+                        header.get_local_depth(),
+                        header.get_suffix()
+                    ));
+                }
+            }
+        }
+        if !subtables.insert(subtable) {
+            continue;
+        }
+        for bucket_group in 0..CONFIG.bucket_group_num {
+            for bucket in 0..CONFIG.bucket_num {
+                for slot in 0..CONFIG.slot_num {
+                    let slot_pos = SlotPos {
+                        subtable,
+                        bucket_group,
+                        bucket,
+                        header: 0,
+                        slot,
+                    };
+                    match mempool.read().unwrap().read_slot_kv(&slot_pos) {
+                        Some(kv) if kv.klen != 0 => {
+                            if RaceUtils::get_suffix(&kv.key, local_depth) != suffix {
+                                return Err(format!(
+                                    "key {} is stored in the subtable of suffix {}",
+                                    kv.key, suffix
+                                ));
+                            }
+                            let item = found.entry(kv.key).or_insert((0, kv.value));
+                            item.0 += 1;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+    for (key, value) in expected {
+        match (value, found.get(key)) {
+            (Some(_), None) => return Err(format!("key {} is lost", key)),
+            (None, Some(_)) => return Err(format!("key {} is deleted but found", key)),
+            (_, Some((count, _))) if *count > 1 => {
+                return Err(format!("key {} is found {} times", key, count))
+            }
+            (Some(value), Some((_, found_value))) if value != found_value => {
+                return Err(format!(
+                    "key {} has value {}, expected {}",
+                    key, found_value, value
+                ))
+            }
+            _ => {}
+        }
+    }
+    if let Some(key) = found.keys().find(|key| !expected.contains_key(*key)) {
+        return Err(format!("key {} is never written", key));
+    }
+    Ok(())
+}
+
+// a split of the first subtable racing with updates and deletes of the items it moves
+pub struct MigrationScenario {
+    pub item_num: usize,
+}
+
+impl MigrationScenario {
+    fn get_key(i: usize) -> String {
+        String::from("key") + &i.to_string()
+    }
+
+    // the items moved by the split, in the order of their bucket groups so that writers follow
+    // the migration
+    fn get_moved_items(&self) -> Vec<usize> {
+        let mut items: Vec<usize> = (0..self.item_num)
+            .filter(|i| RaceUtils::get_suffix(&Self::get_key(*i), CONFIG.min_local_depth) == 0)
+            .collect();
+        items.sort_by_key(|i| {
+            Hash::hash(&Self::get_key(*i), HashMethod::CombinedBucket1) as usize
+                % CONFIG.bucket_group_num
+        });
+        items
+    }
+}
+
+impl Scenario for MigrationScenario {
+    fn get_thread_num(&self) -> usize {
+        3
+    }
+
+    fn setup(&self, client: &mut Client) {
+        for i in 0..self.item_num {
+            client
+                .insert(&Self::get_key(i), &(String::from("val") + &i.to_string()))
+                .unwrap();
+        }
+    }
+
+    fn run(&self, thread: usize, client: &mut Client) {
+        match thread {
+            0 => client.pub_rehash(0).unwrap(),
+            1 => {
+                for i in self.get_moved_items().into_iter().filter(|i| i % 3 == 1) {
+                    assert!(client
+                        .update(&Self::get_key(i), &(String::from("new") + &i.to_string()))
+                        .unwrap());
+                }
+            }
+            _ => {
+                for i in self.get_moved_items().into_iter().filter(|i| i % 3 == 2) {
+                    assert!(client.delete(&Self::get_key(i)).unwrap());
+                }
+            }
+        }
+    }
+
+    fn check(&self, mempool: &Arc<RwLock<MemPool>>, _client: &mut Client) -> Result<(), String> {
+        let moved: HashSet<usize> = self.get_moved_items().into_iter().collect();
+        let expected = (0..self.item_num)
+            .map(|i| {
+                let value = if !moved.contains(&i) || i % 3 == 0 {
+                    Some(String::from("val") + &i.to_string())
+                } else if i % 3 == 1 {
+                    Some(String::from("new") + &i.to_string())
+                } else {
+                    None
+                };
+                (Self::get_key(i), value)
+            })
+            .collect();
+        check_table(mempool, &expected)
+    }
+}
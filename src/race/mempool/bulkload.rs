@@ -0,0 +1,224 @@
+use super::directory::MemPoolDirectory;
+use super::mempool::MemPool;
+use super::subtable::Subtable;
+use crate::cfg::config::CONFIG;
+use crate::numa::mm::{memset, MemoryManager};
+use crate::race::common::hash::{Hash, HashMethod};
+use crate::race::common::kvblock::KVBlockMem;
+use crate::race::common::utils::RaceUtils;
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::sync::Arc;
+
+struct BulkItem {
+    key: String,
+    value: String,
+    hash_key: u64,
+    hash_1: usize,
+    hash_2: usize,
+}
+
+// (item, bucket group, bucket, slot)
+type Placement = (usize, usize, usize, usize);
+
+struct Partition {
+    suffix: u64,
+    local_depth: u8,
+    placements: Vec<Placement>,
+}
+
+// Builds a whole directory offline, without going through the insert path
+pub struct BulkLoader {
+    memory_manager: Arc<MemoryManager>,
+    // blocks carry access records for a pool tracking accesses
+    tracked: bool,
+}
+
+impl BulkLoader {
+    pub fn new(memory_manager: Arc<MemoryManager>, tracked: bool) -> Self {
+        BulkLoader {
+            memory_manager,
+            tracked,
+        }
+    }
+
+    // return the directory, the number of distinct items and the bytes of their blocks
+    pub fn build(&self, kvs: Vec<(String, String)>) -> (MemPoolDirectory, usize, usize) {
+        // the last value of a key wins
+        let mut lasts: HashMap<&str, usize> = HashMap::with_capacity(kvs.len());
+        for (position, (key, _)) in kvs.iter().enumerate() {
+            lasts.insert(key, position);
+        }
+        let mut is_last = vec![false; kvs.len()];
+        for position in lasts.into_values() {
+            is_last[position] = true;
+        }
+        let mut items: Vec<BulkItem> = Vec::with_capacity(kvs.len());
+        for ((key, value), is_last) in kvs.into_iter().zip(is_last) {
+            if !is_last {
+                continue;
+            }
+            items.push(BulkItem {
+                hash_key: Hash::hash(&key, HashMethod::Directory),
+                hash_1: Hash::hash(&key, HashMethod::CombinedBucket1) as usize,
+                hash_2: Hash::hash(&key, HashMethod::CombinedBucket2) as usize,
+                key,
+                value,
+            });
+        }
+
+        // decide the local depth of every suffix
+        let depth = MemPool::get_depth_for_capacity(items.len());
+        let mut members = vec![Vec::new(); RaceUtils::depth_to_size(depth)];
+        for (item, bulk_item) in items.iter().enumerate() {
+            members[RaceUtils::restrict_suffix_to(bulk_item.hash_key, depth) as usize].push(item);
+        }
+        let mut partitions = Vec::new();
+        for (suffix, suffix_members) in members.into_iter().enumerate() {
+            self.plan(
+                &items,
+                suffix as u64,
+                depth,
+                suffix_members,
+                &mut partitions,
+            );
+        }
+        let global_depth = partitions
+            .iter()
+            .map(|partition| partition.local_depth)
+            .max()
+            .unwrap();
+
+        // write all kv blocks, then fill the subtables directly
+        let (kv_blocks, kv_bytes) = self.write_kv_blocks(&items);
+        let mut subtables = Vec::new();
+        for partition in partitions.iter() {
+            let subtable = self.write_subtable(&items, &kv_blocks, partition);
+            subtables.push((partition.suffix, partition.local_depth, subtable as u64));
+        }
+        (
+            MemPoolDirectory::new_with_subtables(
+                self.memory_manager.clone(),
+                global_depth,
+                &subtables,
+            ),
+            items.len(),
+            kv_bytes,
+        )
+    }
+
+    fn plan(
+        &self,
+        items: &[BulkItem],
+        suffix: u64,
+        local_depth: u8,
+        members: Vec<usize>,
+        partitions: &mut Vec<Partition>,
+    ) {
+        if let Some(placements) = BulkLoader::place(items, &members) {
+            partitions.push(Partition {
+                suffix,
+                local_depth,
+                placements,
+            });
+            return;
+        }
+        if RaceUtils::depth_to_size(local_depth) >= CONFIG.max_entry_num {
+            panic!("bulk load error");
+        }
+        // the subtable can not hold all items, split it as the insert path would
+        let new_suffix = RaceUtils::get_new_suffix_from_old(suffix, local_depth);
+        let (new_members, old_members): (Vec<usize>, Vec<usize>) =
+            members.into_iter().partition(|&item| {
+                RaceUtils::restrict_suffix_to(items[item].hash_key, local_depth + 1) == new_suffix
+            });
+        self.plan(items, suffix, local_depth + 1, old_members, partitions);
+        self.plan(items, new_suffix, local_depth + 1, new_members, partitions);
+    }
+
+    fn place(items: &[BulkItem], members: &[usize]) -> Option<Vec<Placement>> {
+        // used slot number of every bucket, the same choice as get_slot
+        let mut used_slot_nums = vec![[0; CONFIG.bucket_num]; CONFIG.bucket_group_num];
+        let mut placements = Vec::new();
+        for &item in members {
+            let (hash_1, hash_2) = (items[item].hash_1, items[item].hash_2);
+            let count = |used: &Vec<[usize; CONFIG.bucket_num]>, group: usize, bucket: usize| {
+                if used[group][bucket] < CONFIG.slot_num {
+                    used[group][bucket]
+                } else {
+                    used[group][bucket] + used[group][1]
+                }
+            };
+            let cb1_count = count(&used_slot_nums, hash_1, 0);
+            let cb2_count = count(&used_slot_nums, hash_2, 2);
+            let (bucket_group, main_bucket, cb_count) = if cb1_count <= cb2_count {
+                (hash_1, 0, cb1_count)
+            } else {
+                (hash_2, 2, cb2_count)
+            };
+            if cb_count >= 2 * CONFIG.slot_num {
+                return None;
+            }
+            let bucket = if cb_count < CONFIG.slot_num {
+                main_bucket
+            } else {
+                1
+            };
+            placements.push((
+                item,
+                bucket_group,
+                bucket,
+                used_slot_nums[bucket_group][bucket],
+            ));
+            used_slot_nums[bucket_group][bucket] += 1;
+        }
+        Some(placements)
+    }
+
+    // the blocks of a size class are laid out contiguously in fresh chunks
+    fn write_kv_blocks(&self, items: &[BulkItem]) -> (Vec<*const KVBlockMem>, usize) {
+        let sizes: Vec<usize> = items
+            .iter()
+            .map(|item| KVBlockMem::get_length(&item.key, &item.value, self.tracked))
+            .collect();
+        let pointers = self.memory_manager.malloc_batch(&sizes);
+        let kv_blocks = items
+            .iter()
+            .zip(pointers)
+            .map(|(item, pointer)| {
+                if pointer.is_null() {
+                    panic!("kvblock malloc failed");
+                }
+                KVBlockMem::new_at(pointer, &item.key, &item.value, self.tracked)
+            })
+            .collect();
+        (kv_blocks, sizes.iter().sum())
+    }
+
+    fn write_subtable(
+        &self,
+        items: &[BulkItem],
+        kv_blocks: &[*const KVBlockMem],
+        partition: &Partition,
+    ) -> *const Subtable {
+        let subtable_pointer = self.memory_manager.malloc_table(size_of::<Subtable>());
+        if subtable_pointer == std::ptr::null_mut() {
+            panic!("malloc failed");
+        }
+        let subtable = subtable_pointer as *mut Subtable;
+        unsafe {
+            memset(subtable_pointer, 0, size_of::<Subtable>() as u32);
+            (*subtable).set_header(partition.local_depth, partition.suffix);
+            // nobody can see the subtable yet, so slots are written without CAS
+            for &(item, bucket_group, bucket, slot) in partition.placements.iter() {
+                (*subtable).bucket_groups[bucket_group].buckets[bucket].slots[slot].data =
+                    RaceUtils::set_data(
+                        &items[item].key,
+                        &items[item].value,
+                        kv_blocks[item] as u64,
+                    );
+            }
+        }
+        subtable
+    }
+}
@@ -1,6 +1,8 @@
 use crate::cfg::config::CONFIG;
 use crate::numa::mm::memcpy;
+use crate::numa::mm::memset;
 use crate::numa::mm::MemoryManager;
+use crate::numa::region::Regions;
 use crate::race::common::hash::Hash;
 use crate::race::common::utils::RaceUtils;
 use crate::race::computepool::directory::ClientDirectory;
@@ -8,8 +10,8 @@ use crate::race::computepool::directory::ClientEntry;
 use crate::race::mempool::subtable::Subtable;
 use std::f32::consts::E;
 use std::mem::size_of;
-use std::sync::atomic::AtomicU8;
-use std::sync::{atomic, Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicU8};
+use std::sync::{atomic, Arc};
 use std::vec;
 
 use super::subtable::BucketGroup;
@@ -19,27 +21,24 @@ pub struct MemPoolEntry {
 }
 
 impl MemPoolEntry {
-    pub fn init(
-        &mut self,
-        memory_manager: Arc<Mutex<MemoryManager>>,
-        local_depth: u8,
-        suffix: u64,
-    ) {
+    pub fn init(&mut self, memory_manager: Arc<MemoryManager>, local_depth: u8, suffix: u64) {
         self.new_subtable(memory_manager, local_depth, suffix);
         self.set_local_depth(local_depth);
     }
 
     pub fn new_subtable(
         &mut self,
-        memory_manager: Arc<Mutex<MemoryManager>>,
+        memory_manager: Arc<MemoryManager>,
         local_depth: u8,
         suffix: u64,
     ) {
-        let subtable_pointer = memory_manager.lock().unwrap().malloc(size_of::<Subtable>());
+        let subtable_pointer = memory_manager.malloc_table(size_of::<Subtable>());
         if subtable_pointer == std::ptr::null_mut() {
             panic!("malloc failed");
         }
         unsafe {
+            // reused memory may keep the slots of a freed subtable
+            memset(subtable_pointer, 0, size_of::<Subtable>() as u32);
             (*(self as *mut Self)).set_subtable_pointer(subtable_pointer as u64);
         }
         self.set_header(local_depth, suffix);
@@ -121,14 +120,17 @@ impl MemPoolEntry {
     }
 
     pub fn get_subtable_pointer(&self) -> u64 {
-        (self.data
-            & !(0xFF
-                << CONFIG.bits_of_byte
-                    * (size_of::<u64>() - size_of::<u8>() - CONFIG.directory_lock_offset))
-            & !(0xFF
-                << CONFIG.bits_of_byte
-                    * (size_of::<u64>() - size_of::<u8>() - CONFIG.directory_localdepth_offset)))
-            as u64
+        Regions::to_local(
+            self.data
+                & !(0xFF
+                    << (CONFIG.bits_of_byte
+                        * (size_of::<u64>() - size_of::<u8>() - CONFIG.directory_lock_offset)))
+                & !(0xFF
+                    << (CONFIG.bits_of_byte
+                        * (size_of::<u64>()
+                            - size_of::<u8>()
+                            - CONFIG.directory_localdepth_offset))),
+        )
     }
 
     pub fn set_subtable_pointer(&mut self, subtable: u64) {
@@ -139,7 +141,7 @@ impl MemPoolEntry {
             & (0xFF
                 << CONFIG.bits_of_byte
                     * (size_of::<u64>() - size_of::<u8>() - CONFIG.directory_localdepth_offset)))
-            | subtable;
+            | Regions::to_remote(subtable);
     }
 
     pub fn try_lock(&mut self, old_data: u64, lock: u8) -> Result<u64, u64> {
@@ -197,33 +199,94 @@ impl MemPoolEntry {
 
 pub struct MemPoolDirectory {
     pub global_depth: *mut u8,
+    pub version: *mut u64,
+    pub epoch: *mut u64,
     pub entries: *mut [MemPoolEntry; CONFIG.max_entry_num],
 }
 
 impl MemPoolDirectory {
-    pub fn new(memory_manager: Arc<Mutex<MemoryManager>>) -> Self {
-        let vec_pointer = memory_manager
-            .lock()
-            .unwrap()
-            .malloc(CONFIG.entry_size * CONFIG.max_entry_num);
-        let gd_pointer = memory_manager.lock().unwrap().malloc(size_of::<u8>());
+    pub fn new(memory_manager: Arc<MemoryManager>) -> Self {
+        MemPoolDirectory::new_with_depth(memory_manager, 1)
+    }
+
+    fn alloc(memory_manager: Arc<MemoryManager>, depth: u8) -> Self {
+        let vec_pointer = memory_manager.malloc_table(CONFIG.entry_size * CONFIG.max_entry_num);
+        let gd_pointer = memory_manager.malloc_table(size_of::<u8>());
+        let version_pointer = memory_manager.malloc_table(size_of::<u64>());
+        let epoch_pointer = memory_manager.malloc_table(size_of::<u64>());
+        if [vec_pointer, gd_pointer, version_pointer, epoch_pointer]
+            .iter()
+            .any(|pointer| pointer.is_null())
+        {
+            panic!("malloc failed");
+        }
         unsafe {
-            (*(vec_pointer as *mut [MemPoolEntry; CONFIG.max_entry_num]))[0].init(
-                memory_manager.clone(),
-                1,
+            memset(
+                vec_pointer,
                 0,
+                (CONFIG.entry_size * CONFIG.max_entry_num) as u32,
             );
-            (*(vec_pointer as *mut [MemPoolEntry; CONFIG.max_entry_num]))[1].init(
-                memory_manager,
-                1,
-                1,
-            );
-            *(gd_pointer as *mut usize) = 1;
-            MemPoolDirectory {
-                global_depth: gd_pointer as *mut u8,
-                entries: vec_pointer as *mut [MemPoolEntry; CONFIG.max_entry_num],
+            *gd_pointer = depth;
+            *(version_pointer as *mut u64) = 0;
+            *(epoch_pointer as *mut u64) = 0;
+        }
+        MemPoolDirectory {
+            global_depth: gd_pointer,
+            version: version_pointer as *mut u64,
+            epoch: epoch_pointer as *mut u64,
+            entries: vec_pointer as *mut [MemPoolEntry; CONFIG.max_entry_num],
+        }
+    }
+
+    pub fn new_with_depth(memory_manager: Arc<MemoryManager>, depth: u8) -> Self {
+        let dir = MemPoolDirectory::alloc(memory_manager.clone(), depth);
+        // every suffix owns a subtable, so no item needs to move before the directory is used
+        for index in 0..RaceUtils::depth_to_size(depth) {
+            dir.get_entry(index)
+                .init(memory_manager.clone(), depth, index as u64);
+        }
+        dir
+    }
+
+    // build a directory over given subtables, each one is (suffix, local depth, pointer)
+    pub fn new_with_subtables(
+        memory_manager: Arc<MemoryManager>,
+        depth: u8,
+        subtables: &[(u64, u8, u64)],
+    ) -> Self {
+        let dir = MemPoolDirectory::alloc(memory_manager, depth);
+        for (suffix, local_depth, pointer) in subtables.iter() {
+            let mut index = *suffix as usize;
+            while index < RaceUtils::depth_to_size(depth) {
+                dir.get_entry(index)
+                    .set_subtable_and_localdepth(*pointer, *local_depth);
+                index = RaceUtils::plus_bit_to_suffix(index as u64, local_depth + 1) as usize;
             }
         }
+        dir
+    }
+
+    // every subtable once, as (suffix, local depth, pointer)
+    pub fn get_subtables(&self) -> Vec<(u64, u8, u64)> {
+        let mut subtables = Vec::new();
+        for index in 0..RaceUtils::depth_to_size(self.get_global_depth()) {
+            let entry = self.get_entry_const(index);
+            let local_depth = entry.get_local_depth();
+            if RaceUtils::restrict_suffix_to(index as u64, local_depth) as usize == index {
+                subtables.push((index as u64, local_depth, entry.get_subtable_pointer()));
+            }
+        }
+        subtables
+    }
+
+    pub fn free(&self, memory_manager: Arc<MemoryManager>) {
+        memory_manager.free_table(
+            self.entries as *const u8,
+            CONFIG.entry_size * CONFIG.max_entry_num,
+        );
+        memory_manager.free_table(self.global_depth as *const u8, size_of::<u8>());
+        memory_manager.free_table(self.version as *const u8, size_of::<u64>());
+        memory_manager.free_table(self.epoch as *const u8, size_of::<u64>());
     }
 
     pub fn set_subtable_header(&self, index: usize, local_depth: u8, suffix: u64) {
@@ -244,6 +307,9 @@ impl MemPoolDirectory {
 
     pub fn get_directory(&self) -> ClientDirectory {
         let mut new_dir_entries = [ClientEntry { data: 0 }; CONFIG.max_entry_num];
+        // read epoch before entries, a change after it will be detected by the next check
+        let new_epoch = self.get_epoch();
+        let new_version = self.get_version();
         let new_global_depth = self.get_global_depth();
         let new_size = RaceUtils::depth_to_size(new_global_depth as u8);
         for index in 0..new_size {
@@ -251,6 +317,8 @@ impl MemPoolDirectory {
         }
         ClientDirectory {
             global_depth: new_global_depth,
+            version: new_version,
+            epoch: new_epoch,
             entries: new_dir_entries,
         }
     }
@@ -267,12 +335,58 @@ impl MemPoolDirectory {
         unsafe { (*(self.global_depth as *mut AtomicU8)).load(std::sync::atomic::Ordering::SeqCst) }
     }
 
+    pub fn get_version(&self) -> u64 {
+        unsafe { (*(self.version as *mut AtomicU64)).load(std::sync::atomic::Ordering::SeqCst) }
+    }
+
+    pub fn set_version(&self, version: u64) {
+        unsafe {
+            (*(self.version as *mut AtomicU64)).store(version, std::sync::atomic::Ordering::SeqCst)
+        }
+    }
+
+    pub fn atomic_add_version(&self, add: u64) {
+        unsafe {
+            (*(self.version as *mut AtomicU64)).fetch_add(add, std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    pub fn get_epoch(&self) -> u64 {
+        unsafe { (*(self.epoch as *mut AtomicU64)).load(std::sync::atomic::Ordering::SeqCst) }
+    }
+
+    pub fn set_epoch(&self, epoch: u64) {
+        unsafe {
+            (*(self.epoch as *mut AtomicU64)).store(epoch, std::sync::atomic::Ordering::SeqCst)
+        }
+    }
+
+    // the epoch before the add
+    pub fn atomic_add_epoch(&self, add: u64) -> u64 {
+        unsafe {
+            (*(self.epoch as *mut AtomicU64)).fetch_add(add, std::sync::atomic::Ordering::SeqCst)
+        }
+    }
+
+    pub fn read_entry(&self, index: usize) -> u64 {
+        unsafe {
+            std::mem::transmute::<&u64, &AtomicU64>(&self.get_entry_const(index).data)
+                .load(std::sync::atomic::Ordering::SeqCst)
+        }
+    }
+
     pub fn atomic_add_global_depth(&self, add: u8) {
         unsafe {
             *(self.global_depth) += add;
         }
     }
 
+    pub fn atomic_sub_global_depth(&self, sub: u8) {
+        unsafe {
+            *(self.global_depth) -= sub;
+        }
+    }
+
     pub fn try_lock_entry(&self, index: usize, old_data: u64, lock: u8) -> Result<u64, u64> {
         self.get_entry(index).try_lock(old_data, lock)
     }
@@ -0,0 +1,173 @@
+use super::mempool::MemPool;
+use crate::race::common::kvblock::KVBlockMem;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+// memory pool operations that can be failed or delayed
+#[derive(Clone, Copy)]
+pub enum FaultOp {
+    WriteSlot = 0,
+    UpdateEntry = 1,
+    TryLockEntry = 2,
+    WriteKv = 3,
+}
+
+const FAULT_OP_NUM: usize = 4;
+
+// steps of rehash and merge where a client can be crashed, it keeps its locks and lease
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CrashPoint {
+    // the suffix pair is locked, nothing is changed yet
+    AfterLock,
+    // the new subtable is published, the entries are still locked
+    AfterSplitEntry,
+    // the entries are unlocked, no item is moved yet
+    BeforeMigration,
+    // a bucket group is claimed and moved, but not marked done
+    DuringMigration,
+    // both headers of a merge are changed, no item is moved yet
+    AfterRetire,
+    // a bucket group of the merged subtable is moved
+    DuringMerge,
+}
+
+// decides which operations fail, all decisions come from one seeded generator, so that a
+// single-threaded run is repeated exactly with the same seed
+pub struct FaultInjector {
+    seed: Mutex<u64>,
+    fail_percents: [AtomicUsize; FAULT_OP_NUM],
+    delays_us: [AtomicU64; FAULT_OP_NUM],
+    corrupt_percent: AtomicUsize,
+    // (client id, crash point), each crashes the client once
+    crash_points: Mutex<Vec<(usize, CrashPoint)>>,
+    injected_num: AtomicUsize,
+}
+
+impl FaultInjector {
+    pub fn new(seed: u64) -> Self {
+        FaultInjector {
+            // xorshift never leaves zero
+            seed: Mutex::new(seed | 1),
+            fail_percents: Default::default(),
+            delays_us: Default::default(),
+            corrupt_percent: AtomicUsize::new(0),
+            crash_points: Mutex::new(Vec::new()),
+            injected_num: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn set_seed(&self, seed: u64) {
+        *self.seed.lock().unwrap() = seed | 1;
+    }
+
+    pub fn set_fail_percent(&self, op: FaultOp, percent: usize) {
+        self.fail_percents[op as usize].store(percent, Ordering::SeqCst);
+    }
+
+    pub fn set_delay_us(&self, op: FaultOp, delay_us: u64) {
+        self.delays_us[op as usize].store(delay_us, Ordering::SeqCst);
+    }
+
+    // corrupt the checksum of newly written kv blocks
+    pub fn set_corrupt_percent(&self, percent: usize) {
+        self.corrupt_percent.store(percent, Ordering::SeqCst);
+    }
+
+    pub fn crash_at(&self, client_id: usize, point: CrashPoint) {
+        self.crash_points.lock().unwrap().push((client_id, point));
+    }
+
+    // stop injecting, the seed and the injected number are kept
+    pub fn clear(&self) {
+        for op in 0..FAULT_OP_NUM {
+            self.fail_percents[op].store(0, Ordering::SeqCst);
+            self.delays_us[op].store(0, Ordering::SeqCst);
+        }
+        self.corrupt_percent.store(0, Ordering::SeqCst);
+        self.crash_points.lock().unwrap().clear();
+    }
+
+    pub fn get_injected_num(&self) -> usize {
+        self.injected_num.load(Ordering::SeqCst)
+    }
+
+    fn next_random(&self) -> u64 {
+        let mut seed = self.seed.lock().unwrap();
+        *seed ^= *seed << 13;
+        *seed ^= *seed >> 7;
+        *seed ^= *seed << 17;
+        *seed
+    }
+
+    fn hit(&self, percent: usize) -> bool {
+        // the generator is not consulted while nothing is injected
+        if percent == 0 || self.next_random() % 100 >= percent as u64 {
+            return false;
+        }
+        self.injected_num.fetch_add(1, Ordering::SeqCst);
+        true
+    }
+
+    // delay the operation, and return true if it should fail
+    pub fn inject(&self, op: FaultOp) -> bool {
+        let delay_us = self.delays_us[op as usize].load(Ordering::SeqCst);
+        if delay_us > 0 {
+            std::thread::sleep(Duration::from_micros(delay_us));
+        }
+        self.hit(self.fail_percents[op as usize].load(Ordering::SeqCst))
+    }
+
+    pub fn inject_corruption(&self, kv_block: *const KVBlockMem) {
+        if self.hit(self.corrupt_percent.load(Ordering::SeqCst)) {
+            FaultInjector::corrupt_kv(kv_block);
+        }
+    }
+
+    pub fn corrupt_kv(kv_block: *const KVBlockMem) {
+        unsafe {
+            (*(kv_block as *mut KVBlockMem)).corrupt();
+        }
+    }
+
+    // true if the client should crash here, the crash point is consumed
+    pub fn take_crash(&self, client_id: usize, point: CrashPoint) -> bool {
+        let mut crash_points = self.crash_points.lock().unwrap();
+        match crash_points
+            .iter()
+            .position(|crash_point| *crash_point == (client_id, point))
+        {
+            Some(index) => {
+                crash_points.swap_remove(index);
+                self.injected_num.fetch_add(1, Ordering::SeqCst);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// a memory pool whose verbs go through a fault injector, clients are built on get_mempool
+pub struct FaultyMemPool {
+    mempool: Arc<RwLock<MemPool>>,
+    faults: Arc<FaultInjector>,
+}
+
+impl FaultyMemPool {
+    pub fn new(mut mempool: MemPool, seed: u64) -> Self {
+        let faults = Arc::new(FaultInjector::new(seed));
+        mempool.set_fault_injector(Some(faults.clone()));
+        FaultyMemPool {
+            mempool: Arc::new(RwLock::new(mempool)),
+            faults,
+        }
+    }
+
+    pub fn get_mempool(&self) -> &Arc<RwLock<MemPool>> {
+        &self.mempool
+    }
+
+    pub fn get_faults(&self) -> &FaultInjector {
+        &self.faults
+    }
+}
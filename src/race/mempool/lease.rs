@@ -0,0 +1,123 @@
+use crate::cfg::config::CONFIG;
+use std::mem::size_of;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// what a client is doing while it holds directory locks, so that others can recover it
+pub enum LockIntent {
+    None = 0,
+    Split = 1,
+    Merge = 2,
+    Resize = 3,
+    Displace = 4,
+    Migrate = 5,
+}
+
+struct Lease {
+    expiry: AtomicU64,
+    // intent kind, local depth and index, the same layout as a directory entry
+    intent: AtomicU64,
+    // the subtable the intent is working on
+    subtable: AtomicU64,
+}
+
+pub struct LeaseTable {
+    leases: Vec<Lease>,
+}
+
+impl LeaseTable {
+    pub fn new() -> Self {
+        LeaseTable {
+            leases: (0..CONFIG.max_client_num)
+                .map(|_| Lease {
+                    expiry: AtomicU64::new(0),
+                    intent: AtomicU64::new(0),
+                    subtable: AtomicU64::new(0),
+                })
+                .collect(),
+        }
+    }
+
+    fn get_lease(&self, owner: usize) -> &Lease {
+        &self.leases[owner - 1]
+    }
+
+    pub fn renew(&self, owner: usize, now: u64) {
+        self.get_lease(owner)
+            .expiry
+            .store(now + CONFIG.lock_lease_ms, Ordering::SeqCst);
+    }
+
+    pub fn release(&self, owner: usize) {
+        let lease = self.get_lease(owner);
+        lease.intent.store(0, Ordering::SeqCst);
+        lease.subtable.store(0, Ordering::SeqCst);
+        lease.expiry.store(0, Ordering::SeqCst);
+    }
+
+    // the owner is running or is being recovered
+    pub fn is_held(&self, owner: usize) -> bool {
+        self.get_lease(owner).expiry.load(Ordering::SeqCst) != 0
+    }
+
+    pub fn is_expired(&self, owner: usize, now: u64) -> bool {
+        let expiry = self.get_lease(owner).expiry.load(Ordering::SeqCst);
+        expiry != 0 && expiry < now
+    }
+
+    // only one client recovers an expired owner, it holds the lease on behalf of the owner
+    pub fn try_claim(&self, owner: usize, now: u64) -> bool {
+        let lease = self.get_lease(owner);
+        let expiry = lease.expiry.load(Ordering::SeqCst);
+        if expiry == 0 || expiry >= now {
+            return false;
+        }
+        lease
+            .expiry
+            .compare_exchange(
+                expiry,
+                now + CONFIG.lock_lease_ms,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            )
+            .is_ok()
+    }
+
+    pub fn get_expired_owners(&self, now: u64) -> Vec<usize> {
+        (1..=CONFIG.max_client_num)
+            .filter(|owner| self.is_expired(*owner, now))
+            .collect()
+    }
+
+    pub fn set_intent(
+        &self,
+        owner: usize,
+        intent: LockIntent,
+        index: usize,
+        local_depth: u8,
+        subtable: u64,
+    ) {
+        let lease = self.get_lease(owner);
+        lease.subtable.store(subtable, Ordering::SeqCst);
+        lease.intent.store(
+            ((intent as u64) << (CONFIG.bits_of_byte * (size_of::<u64>() - size_of::<u8>())))
+                | ((local_depth as u64)
+                    << (CONFIG.bits_of_byte * (size_of::<u64>() - 2 * size_of::<u8>())))
+                | index as u64,
+            Ordering::SeqCst,
+        );
+    }
+
+    // (intent, index, local depth, subtable)
+    pub fn get_intent(&self, owner: usize) -> (u8, usize, u8, u64) {
+        let lease = self.get_lease(owner);
+        let intent = lease.intent.load(Ordering::SeqCst);
+        let index_mask =
+            (1 << (CONFIG.bits_of_byte * (size_of::<u64>() - 2 * size_of::<u8>()))) - 1;
+        (
+            (intent >> (CONFIG.bits_of_byte * (size_of::<u64>() - size_of::<u8>()))) as u8,
+            (intent & index_mask) as usize,
+            (intent >> (CONFIG.bits_of_byte * (size_of::<u64>() - 2 * size_of::<u8>()))) as u8,
+            lease.subtable.load(Ordering::SeqCst),
+        )
+    }
+}
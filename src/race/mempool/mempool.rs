@@ -1,28 +1,145 @@
+use crate::cfg::config::CONFIG;
 use crate::directory::MemPoolDirectory;
 use crate::numa::mm::memset;
-use crate::race::common::kvblock::{KVBlockMem, KVBlock};
+use crate::race::common::kvblock::{KVBlock, KVBlockMem};
+use crate::race::common::utils::RaceUtils;
 use crate::race::computepool::directory::ClientDirectory;
+use crate::race::computepool::eviction::EvictionPolicy;
 use crate::race::mempool::subtable::CombinedBucket;
+use crate::numa::mm::{AllocKind, Placement};
+use crate::numa::numa::HugePages;
 use crate::MemoryManager;
 use std::mem::size_of;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
 
+use super::bulkload::BulkLoader;
 use super::directory::MemPoolEntry;
-use super::subtable::{SlotPos, Subtable};
+#[cfg(any(test, feature = "fault-injection"))]
+use super::fault::{FaultInjector, FaultOp};
+use super::lease::{LeaseTable, LockIntent};
+use super::reclaim::Reclaimer;
+#[cfg(any(test, feature = "fault-injection"))]
+use super::sched::Scheduler;
+use super::subtable::{MigrationState, Slot, SlotPos, Subtable};
+use super::verify::{Verifier, VerifyReport};
 pub struct MemPool {
-    memory_manager: Arc<Mutex<MemoryManager>>,
+    memory_manager: Arc<MemoryManager>,
     dir: MemPoolDirectory,
+    reclaimer: Reclaimer,
+    leases: LeaseTable,
+    // an unreachable memory node, clients get an error instead of accessing it
+    failed: AtomicBool,
+    // installed only by FaultyMemPool, verbs of other pools are not injected
+    #[cfg(any(test, feature = "fault-injection"))]
+    faults: Option<Arc<FaultInjector>>,
+    #[cfg(any(test, feature = "fault-injection"))]
+    sched: Scheduler,
+    // bytes of kv blocks written and not freed, a write passing the quota evicts or fails
+    kv_bytes: AtomicUsize,
+    quota: usize,
+    eviction: Option<Arc<dyn EvictionPolicy>>,
+    // one of every access_sample_num searches of a client records an access, 0 tracks none
+    access_sample_num: usize,
+    access_clock: AtomicU32,
+    // small values are kept in their slots, see set_inline_values
+    inline_values: bool,
 }
 
+// the memory pool is shared by clients, all remote memory is accessed by atomic verbs
+unsafe impl Send for MemPool {}
+unsafe impl Sync for MemPool {}
+
 impl MemPool {
     pub fn new() -> Self {
-        let memory_manager = Arc::new(Mutex::new(MemoryManager::new()));
+        MemPool::with_placement(Placement::Node(CONFIG.default_numa_node))
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        MemPool::build(
+            Arc::new(MemoryManager::new()),
+            MemPool::get_depth_for_capacity(capacity),
+        )
+    }
+
+    pub fn with_placement(placement: Placement) -> Self {
+        MemPool::build(Arc::new(MemoryManager::with_placement(placement)), 1)
+    }
+
+    pub fn with_huge_pages(placement: Placement, huge_pages: HugePages) -> Self {
+        MemPool::build(
+            Arc::new(MemoryManager::with_options(placement, huge_pages)),
+            1,
+        )
+    }
+
+    fn build(memory_manager: Arc<MemoryManager>, depth: u8) -> Self {
         MemPool {
             memory_manager: memory_manager.clone(),
-            dir: MemPoolDirectory::new(memory_manager),
+            dir: MemPoolDirectory::new_with_depth(memory_manager.clone(), depth),
+            reclaimer: Reclaimer::new(memory_manager),
+            leases: LeaseTable::new(),
+            failed: AtomicBool::new(false),
+            #[cfg(any(test, feature = "fault-injection"))]
+            faults: None,
+            #[cfg(any(test, feature = "fault-injection"))]
+            sched: Scheduler::new(),
+            kv_bytes: AtomicUsize::new(0),
+            quota: usize::MAX,
+            eviction: None,
+            access_sample_num: 0,
+            access_clock: AtomicU32::new(0),
+            inline_values: false,
         }
     }
 
+    pub fn get_depth_for_capacity(capacity: usize) -> u8 {
+        // choose the global depth that holds all items below the resize fill ratio
+        let subtable_capacity = Subtable::get_capacity() * CONFIG.resize_fill_percent / 100;
+        let mut depth = CONFIG.min_local_depth;
+        while RaceUtils::depth_to_size(depth) * subtable_capacity < capacity
+            && RaceUtils::depth_to_size(depth) < CONFIG.max_entry_num
+        {
+            depth += 1;
+        }
+        depth
+    }
+
+    pub fn build_directory(&self, items: Vec<(String, String)>) -> (MemPoolDirectory, usize) {
+        let (dir, item_num, kv_bytes) =
+            BulkLoader::new(self.memory_manager.clone(), self.is_access_tracked()).build(items);
+        // its blocks are freed like written ones when they are replaced
+        self.kv_bytes.fetch_add(kv_bytes, Ordering::SeqCst);
+        (dir, item_num)
+    }
+
+    // publish a new directory, the old one is returned and must be retired by the caller
+    pub fn swap_directory(&mut self, dir: MemPoolDirectory) -> MemPoolDirectory {
+        // the layout is changed entirely, clients must not refresh entries one by one
+        dir.set_version(self.dir.get_version() + 1);
+        dir.set_epoch(self.dir.get_epoch() + 1);
+        std::mem::replace(&mut self.dir, dir)
+    }
+
+    pub fn retire_directory(&self, dir: MemPoolDirectory) {
+        for (_, local_depth, pointer) in dir.get_subtables() {
+            let subtable = pointer as *mut Subtable;
+            unsafe {
+                // a suffix out of range never matches, so that stale clients refresh and redo
+                (*subtable).set_header(local_depth, RaceUtils::depth_to_size(local_depth) as u64);
+                for bucket_group in (*subtable).bucket_groups.iter() {
+                    for bucket in bucket_group.buckets.iter() {
+                        for slot in bucket.slots.iter() {
+                            self.free_data(slot.get_data());
+                        }
+                    }
+                }
+            }
+            self.free_subtable(subtable);
+        }
+        dir.free(self.memory_manager.clone());
+    }
+
     pub fn read(
         &mut self,
         index: usize,
@@ -32,57 +149,226 @@ impl MemPool {
         self.dir.get(index, bucket1, bucket2)
     }
 
+    // null if the allocation fails
     pub fn write_kv(&self, key: String, value: String) -> *const KVBlockMem {
-        KVBlockMem::new(&key, &value, self.memory_manager.clone())
+        self.yield_point();
+        #[cfg(any(test, feature = "fault-injection"))]
+        if self.inject(FaultOp::WriteKv) {
+            return std::ptr::null();
+        }
+        let kv_block = KVBlockMem::new(
+            &key,
+            &value,
+            self.is_access_tracked(),
+            self.memory_manager.clone(),
+        );
+        if kv_block.is_null() {
+            return kv_block;
+        }
+        self.kv_bytes
+            .fetch_add(unsafe { (*kv_block).get_total_length() }, Ordering::SeqCst);
+        // a new item is as recent as the last access, so that it is not the first one evicted
+        unsafe { (*kv_block).set_last_access(self.get_access_tick()) };
+        #[cfg(any(test, feature = "fault-injection"))]
+        if let Some(faults) = &self.faults {
+            faults.inject_corruption(kv_block);
+        }
+        kv_block
     }
 
+    // clients may still read the block, it is freed once all of them leave their operations
     pub fn free_kv(&self, kv_block: *const KVBlockMem, size: usize) {
-        unsafe {
-            memset(kv_block as *mut u8, 0, size as u32);
+        self.yield_point();
+        self.kv_bytes.fetch_sub(size, Ordering::SeqCst);
+        self.reclaimer
+            .retire(kv_block as *const u8, size, AllocKind::Kv);
+    }
+
+    // the kv block of the data of a slot, an empty slot or an inline item has none
+    pub fn free_data(&self, data: u64) {
+        let slot = Slot { data };
+        if slot.judge_empty() || slot.is_inline() {
+            return;
         }
-        self.memory_manager
-            .lock()
-            .unwrap()
-            .free(kv_block as *const u8, size);
+        let kv_block = slot.get_kv_pointer();
+        self.free_kv(kv_block, unsafe { (*kv_block).get_total_length() });
+    }
+
+    // writes are rejected past the quota without a policy, otherwise clients evict items chosen
+    // by the policy until the write fits; none removes the quota
+    pub fn set_quota(&mut self, quota: Option<usize>, eviction: Option<Arc<dyn EvictionPolicy>>) {
+        self.quota = quota.unwrap_or(usize::MAX);
+        self.eviction = eviction;
+    }
+
+    // the quota is checked before a write, so that racing writers may pass it a little
+    pub fn is_over_quota(&self, size: usize) -> bool {
+        self.kv_bytes.load(Ordering::SeqCst).saturating_add(size) > self.quota
+    }
+
+    pub fn get_eviction_policy(&self) -> Option<Arc<dyn EvictionPolicy>> {
+        self.eviction.clone()
+    }
+
+    pub fn get_kv_bytes(&self) -> usize {
+        self.kv_bytes.load(Ordering::SeqCst)
+    }
+
+    // items whose key and value together fit into inline_data_size (7) bytes are kept in their
+    // slots without a kv block; the slot holds the whole key, since a value with only a hash of
+    // its key would take another key of the same hash for it, so longer keys always get a kv
+    // block. Such an item is matched and scanned like any other; it is not compacted, and it
+    // carries no access metadata
+    pub fn set_inline_values(&mut self, inline_values: bool) {
+        self.inline_values = inline_values;
+    }
+
+    pub fn is_inline_enabled(&self) -> bool {
+        self.inline_values
+    }
+
+    // searches keep the hotness of items for the coldest eviction, 0 stops it; only blocks
+    // written while it is on have room for it
+    pub fn set_access_tracking(&mut self, sample_num: usize) {
+        self.access_sample_num = sample_num;
+    }
+
+    pub fn get_access_sample_num(&self) -> usize {
+        self.access_sample_num
+    }
+
+    pub fn is_access_tracked(&self) -> bool {
+        self.access_sample_num != 0
+    }
+
+    // the clock ticks once per recorded access, ages are taken by wrapping subtraction
+    pub fn next_access_tick(&self) -> u32 {
+        self.access_clock
+            .fetch_add(1, Ordering::Relaxed)
+            .wrapping_add(1)
+    }
+
+    pub fn get_access_tick(&self) -> u32 {
+        self.access_clock.load(Ordering::Relaxed)
+    }
+
+    pub fn register_client(&self) -> usize {
+        self.reclaimer.register()
+    }
+
+    pub fn unregister_client(&self, id: usize) {
+        self.reclaimer.unregister(id)
+    }
+
+    pub fn pin(&self, id: usize) {
+        self.reclaimer.pin(id)
+    }
+
+    pub fn unpin(&self, id: usize) {
+        self.reclaimer.unpin(id)
+    }
+
+    pub fn collect(&self) {
+        self.reclaimer.collect()
+    }
+
+    pub fn get_retired_num(&self) -> usize {
+        self.reclaimer.get_retired_num()
     }
 
     pub fn read_slot(&self, slot_pos: &SlotPos) -> u64 {
+        self.yield_point();
         unsafe { (*(slot_pos.subtable as *mut Subtable)).get(slot_pos) }
     }
 
     pub fn read_slot_kv(&self, slot_pos: &SlotPos) -> Option<KVBlock> {
+        self.yield_point();
         unsafe { (*(slot_pos.subtable as *mut Subtable)).get_kv(slot_pos) }
     }
 
     pub fn write_slot(&self, slot_pos: &SlotPos, data: u64, old: u64) -> bool {
+        self.yield_point();
+        #[cfg(any(test, feature = "fault-injection"))]
+        if self.inject(FaultOp::WriteSlot) {
+            return false;
+        }
         unsafe { (*(slot_pos.subtable as *mut Subtable)).set(slot_pos, data, old) }
     }
 
     pub fn write_new_entry(&self, index: usize, data: u64) -> bool {
+        self.yield_point();
         self.dir.write_new_entry(index, data)
     }
 
     pub fn update_entry(&self, index: usize, old_data: u64, new_data: u64) -> bool {
+        self.yield_point();
+        #[cfg(any(test, feature = "fault-injection"))]
+        if self.inject(FaultOp::UpdateEntry) {
+            return false;
+        }
         self.dir.update_entry(index, old_data, new_data)
     }
 
     pub fn new_subtable(&self, local_depth: u8, suffix: u64) -> *const Subtable {
-        let subtable_pointer = self
-            .memory_manager
-            .clone()
-            .lock()
-            .unwrap()
-            .malloc(size_of::<Subtable>());
+        let subtable_pointer = self.memory_manager.malloc_table(size_of::<Subtable>());
         if subtable_pointer == std::ptr::null_mut() {
             panic!("malloc failed");
         }
         unsafe {
+            // reused memory may keep the slots of a freed subtable
+            memset(subtable_pointer, 0, size_of::<Subtable>() as u32);
             (*(subtable_pointer as *mut Subtable)).set_header(local_depth, suffix);
         }
         subtable_pointer as *const Subtable
     }
 
+    pub fn free_subtable(&self, subtable: *const Subtable) {
+        self.reclaimer.retire(
+            subtable as *const u8,
+            size_of::<Subtable>(),
+            AllocKind::Table,
+        );
+    }
+
+    pub fn count_subtable(&self, subtable: *const Subtable) -> usize {
+        unsafe { (*subtable).get_non_empty_slot_num() }
+    }
+
+    // reads only resize_sample_group_num bucket groups
+    pub fn sample_subtable(&self, subtable: *const Subtable) -> usize {
+        unsafe { (*subtable).sample_non_empty_slot_num(CONFIG.resize_sample_group_num) }
+    }
+
+    pub fn start_migration(&self, subtable: *const Subtable) {
+        unsafe { (*(subtable as *mut Subtable)).set_migration_state(MigrationState::Pending) }
+    }
+
+    pub fn try_claim_migration(&self, subtable: *const Subtable, bucket_group: usize) -> bool {
+        self.yield_point();
+        unsafe { (*(subtable as *mut Subtable)).try_claim_migration(bucket_group) }
+    }
+
+    pub fn get_migration_state(&self, subtable: *const Subtable, bucket_group: usize) -> u8 {
+        self.yield_point();
+        unsafe { (*subtable).get_migration_state(bucket_group) }
+    }
+
+    pub fn reset_migration(&self, subtable: *const Subtable, bucket_group: usize) -> bool {
+        self.yield_point();
+        unsafe { (*(subtable as *mut Subtable)).reset_migration(bucket_group) }
+    }
+
+    pub fn finish_migration(&self, subtable: *const Subtable, bucket_group: usize) {
+        self.yield_point();
+        unsafe { (*(subtable as *mut Subtable)).finish_migration(bucket_group) }
+    }
+
+    pub fn is_migrating(&self, subtable: *const Subtable) -> bool {
+        unsafe { (*subtable).is_migrating() }
+    }
+
     pub fn set_subtable_header(&self, index: usize, local_depth: u8, suffix: u64) {
+        self.yield_point();
         self.dir.set_subtable_header(index, local_depth, suffix);
     }
 
@@ -94,18 +380,163 @@ impl MemPool {
         self.dir.atomic_add_global_depth(1)
     }
 
-    pub fn try_lock_entry(&self, index: usize, old_data: u64) -> Result<u64, u64> {
-        self.dir.try_lock_entry(index, old_data, 1)
+    pub fn decrease_global_depth(&self) {
+        self.dir.atomic_sub_global_depth(1)
+    }
+
+    pub fn get_directory_version(&self) -> u64 {
+        self.yield_point();
+        self.dir.get_version()
+    }
+
+    pub fn increase_directory_version(&self) {
+        self.dir.atomic_add_version(1)
+    }
+
+    pub fn get_directory_epoch(&self) -> u64 {
+        self.yield_point();
+        self.dir.get_epoch()
+    }
+
+    // the new epoch
+    pub fn increase_directory_epoch(&self) -> u64 {
+        self.dir.atomic_add_epoch(1) + 1
+    }
+
+    pub fn read_entry(&self, index: usize) -> u64 {
+        self.yield_point();
+        self.dir.read_entry(index)
+    }
+
+    pub fn try_lock_entry(&self, index: usize, old_data: u64, owner: u8) -> Result<u64, u64> {
+        self.yield_point();
+        #[cfg(any(test, feature = "fault-injection"))]
+        if self.inject(FaultOp::TryLockEntry) {
+            return Err(self.read_entry(index));
+        }
+        self.dir.try_lock_entry(index, old_data, owner)
+    }
+
+    pub fn renew_lease(&self, owner: usize) {
+        self.leases.renew(owner, RaceUtils::get_time_ms())
+    }
+
+    pub fn release_lease(&self, owner: usize) {
+        self.leases.release(owner)
+    }
+
+    pub fn try_claim_lease(&self, owner: usize) -> bool {
+        self.leases.try_claim(owner, RaceUtils::get_time_ms())
+    }
+
+    pub fn get_expired_owners(&self) -> Vec<usize> {
+        self.leases.get_expired_owners(RaceUtils::get_time_ms())
+    }
+
+    pub fn set_lock_intent(
+        &self,
+        owner: usize,
+        intent: LockIntent,
+        index: usize,
+        local_depth: u8,
+        subtable: u64,
+    ) {
+        self.leases
+            .set_intent(owner, intent, index, local_depth, subtable)
+    }
+
+    pub fn get_lock_intent(&self, owner: usize) -> (u8, usize, u8, u64) {
+        self.leases.get_intent(owner)
+    }
+
+    // (local depth, suffix) in the header of a subtable
+    pub fn read_subtable_header(&self, subtable: *const Subtable) -> (u8, u64) {
+        unsafe {
+            let header = (*subtable).bucket_groups[0].buckets[0].get_header_atomic();
+            (header.get_local_depth(), header.get_suffix())
+        }
+    }
+
+    pub fn get_global_depth(&self) -> u8 {
+        self.dir.get_global_depth()
     }
 
     pub fn unlock_entry(&self, index: usize, old_data: u64) -> Result<u64, u64> {
+        self.yield_point();
         self.dir.try_unlock_entry(index, old_data)
     }
 
     pub fn get_directory(&self) -> ClientDirectory {
+        self.yield_point();
         self.dir.get_directory()
     }
 
+    pub fn is_failed(&self) -> bool {
+        self.failed.load(Ordering::SeqCst)
+    }
+
+    // only for fault injection, the memory is kept as it was when the node failed
+    pub fn set_failed(&self, failed: bool) {
+        self.failed.store(failed, Ordering::SeqCst);
+    }
+
+    // safe on a live table
+    pub fn verify(&self) -> VerifyReport {
+        Verifier::new(&self.dir, &self.leases, 0).run()
+    }
+
+    pub fn release_orphan_locks(&self, owner: u8) -> VerifyReport {
+        Verifier::new(&self.dir, &self.leases, owner).run_locks()
+    }
+
+    // the owner must hold the locks of all entries, so that no split, merge or resize runs
+    // meanwhile
+    pub fn repair(&self, owner: u8) -> VerifyReport {
+        Verifier::new(&self.dir, &self.leases, owner).run()
+    }
+
+    pub fn get_memory_manager(&self) -> &Arc<MemoryManager> {
+        &self.memory_manager
+    }
+
+    #[cfg(any(test, feature = "fault-injection"))]
+    pub fn set_fault_injector(&mut self, faults: Option<Arc<FaultInjector>>) {
+        self.faults = faults;
+    }
+
+    #[cfg(any(test, feature = "fault-injection"))]
+    pub fn get_fault_injector(&self) -> Option<&FaultInjector> {
+        self.faults.as_deref()
+    }
+
+    // true if the verb should fail
+    #[cfg(any(test, feature = "fault-injection"))]
+    fn inject(&self, op: FaultOp) -> bool {
+        match &self.faults {
+            Some(faults) => faults.inject(op),
+            None => false,
+        }
+    }
+
+    #[cfg(any(test, feature = "fault-injection"))]
+    pub fn get_scheduler(&self) -> &Scheduler {
+        &self.sched
+    }
+
+    // clients read buckets through their cached pointers, it is a remote verb too; the
+    // scheduler only exists with fault injection, without it this is a no-op
+    #[inline(always)]
+    pub fn yield_point(&self) {
+        #[cfg(any(test, feature = "fault-injection"))]
+        self.sched.yield_point();
+    }
+
+    #[inline(always)]
+    pub fn yield_waiting(&self) {
+        #[cfg(any(test, feature = "fault-injection"))]
+        self.sched.yield_waiting();
+    }
+
     // only for test
     pub fn get_entry(&self, index: usize) -> &MemPoolEntry {
         self.dir.get_entry_const(index)
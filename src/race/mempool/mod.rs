@@ -1,3 +1,11 @@
+pub mod bulkload;
 pub mod directory;
+#[cfg(any(test, feature = "fault-injection"))]
+pub mod fault;
+pub mod lease;
 pub mod mempool;
+pub mod reclaim;
+#[cfg(any(test, feature = "fault-injection"))]
+pub mod sched;
 pub mod subtable;
+pub mod verify;
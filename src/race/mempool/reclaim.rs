@@ -0,0 +1,131 @@
+use crate::cfg::config::CONFIG;
+use crate::numa::mm::{memset, AllocKind};
+use crate::MemoryManager;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+// a client not inside any operation
+const INACTIVE_EPOCH: u64 = u64::MAX;
+
+struct Retired {
+    ptr: *const u8,
+    size: usize,
+    kind: AllocKind,
+    epoch: u64,
+}
+
+pub struct Reclaimer {
+    memory_manager: Arc<MemoryManager>,
+    global_epoch: AtomicU64,
+    // epoch observed by each client when it entered its current operation
+    local_epochs: Vec<AtomicU64>,
+    registered: Mutex<Vec<bool>>,
+    retired: Mutex<Vec<Retired>>,
+}
+
+impl Reclaimer {
+    pub fn new(memory_manager: Arc<MemoryManager>) -> Self {
+        Reclaimer {
+            memory_manager,
+            global_epoch: AtomicU64::new(0),
+            local_epochs: (0..CONFIG.max_client_num)
+                .map(|_| AtomicU64::new(INACTIVE_EPOCH))
+                .collect(),
+            registered: Mutex::new(vec![false; CONFIG.max_client_num]),
+            retired: Mutex::new(Vec::new()),
+        }
+    }
+
+    // client id starts from 1, so that 0 is never a valid owner
+    pub fn register(&self) -> usize {
+        let mut registered = self.registered.lock().unwrap();
+        match registered.iter().position(|used| !used) {
+            Some(index) => {
+                registered[index] = true;
+                index + 1
+            }
+            None => panic!("too many clients"),
+        }
+    }
+
+    pub fn unregister(&self, id: usize) {
+        self.unpin(id);
+        self.registered.lock().unwrap()[id - 1] = false;
+        self.collect();
+    }
+
+    pub fn pin(&self, id: usize) {
+        let epoch = self.global_epoch.load(Ordering::SeqCst);
+        self.local_epochs[id - 1].store(epoch, Ordering::SeqCst);
+    }
+
+    pub fn unpin(&self, id: usize) {
+        self.local_epochs[id - 1].store(INACTIVE_EPOCH, Ordering::SeqCst);
+    }
+
+    // the memory must be unreachable from the directory before it is retired
+    pub fn retire(&self, ptr: *const u8, size: usize, kind: AllocKind) {
+        let retired_num = {
+            let mut retired = self.retired.lock().unwrap();
+            retired.push(Retired {
+                ptr,
+                size,
+                kind,
+                epoch: self.global_epoch.load(Ordering::SeqCst),
+            });
+            retired.len()
+        };
+        if retired_num >= CONFIG.reclaim_batch_num {
+            self.collect();
+        }
+    }
+
+    fn get_min_active_epoch(&self) -> u64 {
+        self.local_epochs
+            .iter()
+            .map(|epoch| epoch.load(Ordering::SeqCst))
+            .min()
+            .unwrap_or(INACTIVE_EPOCH)
+    }
+
+    pub fn collect(&self) {
+        let mut retired = self.retired.lock().unwrap();
+
+        // every active client has seen the current epoch, so it can move on
+        let global_epoch = self.global_epoch.load(Ordering::SeqCst);
+        let mut min_epoch = self.get_min_active_epoch();
+        if min_epoch >= global_epoch {
+            let _ = self.global_epoch.compare_exchange(
+                global_epoch,
+                global_epoch + 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            );
+            min_epoch = std::cmp::min(
+                self.get_min_active_epoch(),
+                self.global_epoch.load(Ordering::SeqCst),
+            );
+        }
+
+        // memory retired before the oldest active client entered can not be referenced
+        let mut index = 0;
+        while index < retired.len() {
+            if retired[index].epoch < min_epoch {
+                let r = retired.swap_remove(index);
+                unsafe {
+                    memset(r.ptr as *mut u8, 0, r.size as u32);
+                }
+                match r.kind {
+                    AllocKind::Table => self.memory_manager.free_table(r.ptr, r.size),
+                    AllocKind::Kv => self.memory_manager.free(r.ptr, r.size),
+                }
+            } else {
+                index += 1;
+            }
+        }
+    }
+
+    pub fn get_retired_num(&self) -> usize {
+        self.retired.lock().unwrap().len()
+    }
+}
@@ -0,0 +1,150 @@
+use crate::cfg::config::CONFIG;
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex};
+
+thread_local! {
+    // the simulated thread running on this os thread, none for ordinary threads
+    static SIM_THREAD: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+struct SchedState {
+    running: Option<usize>,
+    alive: Vec<bool>,
+    // threads chosen first, the policy decides once it runs out
+    schedule: Vec<usize>,
+    // random choices, or without a seed the running thread goes on until it waits for others
+    seed: Option<u64>,
+    // every thread chosen so far, replaying it repeats the run
+    trace: Vec<usize>,
+    exceeded: bool,
+}
+
+impl SchedState {
+    fn next_random(&mut self) -> u64 {
+        let mut seed = self.seed.unwrap();
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        self.seed = Some(seed);
+        seed
+    }
+
+    fn choose(&mut self, current: usize, waiting: bool) -> usize {
+        let alive: Vec<usize> = (0..self.alive.len()).filter(|t| self.alive[*t]).collect();
+        let step = self.trace.len();
+        let chosen = if step < self.schedule.len() && self.alive[self.schedule[step]] {
+            self.schedule[step]
+        } else if self.seed.is_some() && step >= self.schedule.len() {
+            alive[(self.next_random() % alive.len() as u64) as usize]
+        } else if !waiting && current < self.alive.len() && self.alive[current] {
+            current
+        } else {
+            // round robin, a finished thread in the schedule is skipped the same way
+            *alive.iter().find(|t| **t > current).unwrap_or(&alive[0])
+        };
+        self.trace.push(chosen);
+        chosen
+    }
+}
+
+// runs one simulated thread at a time, and switches threads only at remote verbs, so that an
+// interleaving is decided by the sequence of chosen threads alone
+pub struct Scheduler {
+    enabled: AtomicBool,
+    state: Mutex<SchedState>,
+    cond: Condvar,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Scheduler {
+            enabled: AtomicBool::new(false),
+            state: Mutex::new(SchedState {
+                running: None,
+                alive: Vec::new(),
+                schedule: Vec::new(),
+                seed: None,
+                trace: Vec::new(),
+                exceeded: false,
+            }),
+            cond: Condvar::new(),
+        }
+    }
+
+    // the first thread is chosen by the schedule too
+    pub fn start(&self, thread_num: usize, schedule: Vec<usize>, seed: Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+        state.alive = vec![true; thread_num];
+        state.schedule = schedule;
+        // xorshift never leaves zero
+        state.seed = seed.map(|seed| seed | 1);
+        state.trace = Vec::new();
+        state.exceeded = false;
+        state.running = Some(state.choose(thread_num, false));
+        self.enabled.store(true, Ordering::SeqCst);
+    }
+
+    // (trace, whether the step limit was exceeded)
+    pub fn stop(&self) -> (Vec<usize>, bool) {
+        self.enabled.store(false, Ordering::SeqCst);
+        let mut state = self.state.lock().unwrap();
+        state.running = None;
+        (std::mem::take(&mut state.trace), state.exceeded)
+    }
+
+    fn wait_for_turn(&self, me: usize, mut state: std::sync::MutexGuard<SchedState>) {
+        while self.enabled.load(Ordering::SeqCst) && state.running != Some(me) {
+            state = self.cond.wait(state).unwrap();
+        }
+    }
+
+    pub fn enter_thread(&self, me: usize) {
+        SIM_THREAD.with(|thread| thread.set(Some(me)));
+        self.wait_for_turn(me, self.state.lock().unwrap());
+    }
+
+    pub fn exit_thread(&self, me: usize) {
+        SIM_THREAD.with(|thread| thread.set(None));
+        let mut state = self.state.lock().unwrap();
+        state.alive[me] = false;
+        if state.alive.iter().any(|alive| *alive) {
+            state.running = Some(state.choose(me, false));
+        }
+        self.cond.notify_all();
+    }
+
+    // called before every remote verb, the chosen thread goes on
+    pub fn yield_point(&self) {
+        self.switch(false);
+    }
+
+    // called by a thread waiting for others, so that they can make progress
+    pub fn yield_waiting(&self) {
+        self.switch(true);
+    }
+
+    fn switch(&self, waiting: bool) {
+        if !self.enabled.load(Ordering::SeqCst) {
+            return;
+        }
+        let me = match SIM_THREAD.with(|thread| thread.get()) {
+            Some(me) => me,
+            None => return,
+        };
+        let mut state = self.state.lock().unwrap();
+        if state.trace.len() >= CONFIG.sim_max_steps {
+            // a livelocked run is given up, threads run freely to their end
+            state.exceeded = true;
+            self.enabled.store(false, Ordering::SeqCst);
+            self.cond.notify_all();
+            return;
+        }
+        let next = state.choose(me, waiting);
+        state.running = Some(next);
+        if next != me {
+            self.cond.notify_all();
+            self.wait_for_turn(me, state);
+        }
+    }
+}
@@ -1,9 +1,11 @@
 use crate::cfg::config::CONFIG;
 use crate::numa::mm::memcpy;
 use crate::numa::mm::MemoryManager;
+use crate::numa::region::Regions;
 use crate::race::common::hash::{Hash, HashMethod};
 use crate::race::common::kvblock::KVBlock;
 use crate::race::common::kvblock::KVBlockMem;
+use crate::race::common::utils::RaceUtils;
 use std::clone;
 use std::mem::size_of;
 use std::sync::{atomic, Arc, Mutex};
@@ -58,10 +60,60 @@ impl Slot {
             as u8
     }
 
+    // the value is kept in the slot instead of a kv block
+    pub fn is_inline(&self) -> bool {
+        self.get_length() & CONFIG.slot_inline_tag != 0
+    }
+
+    // the key and then the value of an inline item, without the length byte
+    fn get_inline_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.data.to_le_bytes().to_vec();
+        bytes.remove(RaceUtils::get_inline_len_index());
+        bytes
+    }
+
+    fn get_inline_lengths(&self) -> (usize, usize) {
+        let len = self.get_length() & !CONFIG.slot_inline_tag;
+        (
+            (len >> CONFIG.inline_len_bits) as usize,
+            (len & ((1 << CONFIG.inline_len_bits) - 1)) as usize,
+        )
+    }
+
+    fn get_inline_key(&self) -> String {
+        let (klen, _) = self.get_inline_lengths();
+        String::from_utf8(self.get_inline_bytes()[..klen].to_vec()).unwrap()
+    }
+
+    fn get_inline_value(&self) -> String {
+        let (klen, vlen) = self.get_inline_lengths();
+        String::from_utf8(self.get_inline_bytes()[klen..klen + vlen].to_vec()).unwrap()
+    }
+
+    // an inline item is read without a kv block
+    fn get_inline_kv(&self) -> KVBlock {
+        let key = self.get_inline_key();
+        let value = self.get_inline_value();
+        KVBlock {
+            klen: key.len() as u16,
+            vlen: value.len() as u16,
+            crc64: RaceUtils::get_crc(&key, &value),
+            key,
+            value,
+        }
+    }
+
     pub fn get_by_key(&self, key: &String, fp: u8) -> Option<KVBlock> {
         if self.get_length() == 0 {
             return None;
         }
+        if self.is_inline() {
+            // the slot holds the whole key
+            if self.get_inline_key() != *key {
+                return None;
+            }
+            return Some(self.get_inline_kv());
+        }
         if self.get_fingerprint() == fp {
             let kv_pointer = self.get_kv_pointer();
             let kv = unsafe { (*(kv_pointer as *mut KVBlockMem)).get() };
@@ -83,14 +135,15 @@ impl Slot {
     }
 
     pub fn get_kv_pointer(&self) -> *const KVBlockMem {
-        (self.data
-            & !(0xFF
-                << CONFIG.bits_of_byte
-                    * (size_of::<u64>() - size_of::<u8>() - CONFIG.slot_fp_offset))
-            & !(0xFF
-                << CONFIG.bits_of_byte
-                    * (size_of::<u64>() - size_of::<u8>() - CONFIG.slot_len_offset)))
-            as *const KVBlockMem
+        Regions::to_local(
+            self.data
+                & !(0xFF
+                    << (CONFIG.bits_of_byte
+                        * (size_of::<u64>() - size_of::<u8>() - CONFIG.slot_fp_offset)))
+                & !(0xFF
+                    << (CONFIG.bits_of_byte
+                        * (size_of::<u64>() - size_of::<u8>() - CONFIG.slot_len_offset))),
+        ) as *const KVBlockMem
     }
 
     pub fn get_data(&self) -> u64 {
@@ -104,9 +157,13 @@ impl Slot {
         }
     }
 
+    // an inline item is read from the slot itself
     pub fn get_kv(&self) -> Option<KVBlock> {
+        if self.is_inline() {
+            return Some(self.get_inline_kv());
+        }
         let kv_pointer = self.get_kv_pointer();
-        if kv_pointer == std::ptr::null_mut() {
+        if kv_pointer.is_null() {
             return None;
         }
         let kv = unsafe { (*(kv_pointer as *mut KVBlockMem)).get() };
@@ -120,6 +177,14 @@ impl Slot {
     }
 }
 
+// The migration state of a bucket group in the new subtable of an incremental split
+#[derive(Clone, Copy, PartialEq)]
+pub enum MigrationState {
+    Done = 0,
+    Pending = 1,
+    Running = 2,
+}
+
 pub struct Header {
     pub data: u64,
 }
@@ -154,21 +219,66 @@ impl Header {
     }
 
     pub fn get_suffix(&self) -> u64 {
-        (self.data
-            & !(0xFF
-                << CONFIG.bits_of_byte
-                    * (size_of::<u64>() - size_of::<u8>() - CONFIG.header_local_depth_offset)))
-            as u64
+        self.data
+            & ((1 << (CONFIG.bits_of_byte * (size_of::<u64>() - CONFIG.header_suffix_offset))) - 1)
     }
 
     pub fn set_suffix(&mut self, suffix: u64) {
         self.data = (self.data
-            & (0xFF
-                << CONFIG.bits_of_byte
-                    * (size_of::<u64>() - size_of::<u8>() - CONFIG.header_local_depth_offset)))
+            & !((1 << (CONFIG.bits_of_byte * (size_of::<u64>() - CONFIG.header_suffix_offset)))
+                - 1))
             | suffix;
     }
 
+    pub fn get_migration_state(&self) -> u8 {
+        (self.data
+            >> (CONFIG.bits_of_byte
+                * (size_of::<u64>() - size_of::<u8>() - CONFIG.header_migration_offset)))
+            as u8
+    }
+
+    fn set_migration_state_data(data: u64, state: MigrationState) -> u64 {
+        (data
+            & !(0xFF
+                << (CONFIG.bits_of_byte
+                    * (size_of::<u64>() - size_of::<u8>() - CONFIG.header_migration_offset))))
+            | ((state as u64)
+                << (CONFIG.bits_of_byte
+                    * (size_of::<u64>() - size_of::<u8>() - CONFIG.header_migration_offset)))
+    }
+
+    pub fn set_migration_state(&mut self, state: MigrationState) {
+        self.data = Header::set_migration_state_data(self.data, state);
+    }
+
+    pub fn clear_migration_state(&mut self) {
+        let atomic_data = unsafe { std::mem::transmute::<&u64, &atomic::AtomicU64>(&self.data) };
+        let mut old_data = atomic_data.load(atomic::Ordering::SeqCst);
+        while let Err(data) = atomic_data.compare_exchange(
+            old_data,
+            Header::set_migration_state_data(old_data, MigrationState::Done),
+            atomic::Ordering::SeqCst,
+            atomic::Ordering::SeqCst,
+        ) {
+            old_data = data;
+        }
+    }
+
+    pub fn try_set_migration_state(&mut self, old: MigrationState, new: MigrationState) -> bool {
+        let old_data = Header::set_migration_state_data(self.clone().data, old);
+        let new_data = Header::set_migration_state_data(old_data, new);
+        unsafe {
+            std::mem::transmute::<&u64, &atomic::AtomicU64>(&self.data)
+                .compare_exchange(
+                    old_data,
+                    new_data,
+                    atomic::Ordering::SeqCst,
+                    atomic::Ordering::SeqCst,
+                )
+                .is_ok()
+        }
+    }
+
     pub fn get_data(&self) -> u64 {
         self.data
     }
@@ -193,6 +303,10 @@ impl Bucket {
         used_slot_num
     }
 
+    pub fn get_non_empty_slot_num(&self) -> usize {
+        self.slots.iter().filter(|slot| !slot.judge_empty()).count()
+    }
+
     pub fn set(&mut self, slot: usize, data: u64, old: u64) -> bool {
         self.slots[slot].compare_and_swap(data, old)
     }
@@ -268,6 +382,16 @@ impl CombinedBucket {
         }
     }
 
+    // the item of the key and the data of its slot
+    pub fn get_by_key_and_data(&self, key: &String) -> Option<(KVBlock, u64)> {
+        let fp = Hash::hash(key, HashMethod::FingerPrint) as u8;
+        self.main_bucket
+            .slots
+            .iter()
+            .chain(self.overflow_bucket.slots.iter())
+            .find_map(|slot| slot.get_by_key(key, fp).map(|v| (v, slot.get_data())))
+    }
+
     pub fn get_slot_pos_and_data(&self, key: &String, hash_type: usize) -> Option<(SlotPos, u64)> {
         let string_to_key = Hash::hash(key, HashMethod::Directory);
         let fp = Hash::hash(key, HashMethod::FingerPrint) as u8;
@@ -325,6 +449,44 @@ impl BucketGroup {
             bucket.set_header(local_depth, suffix);
         }
     }
+
+    pub fn get_non_empty_slot_num(&self) -> usize {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.get_non_empty_slot_num())
+            .sum()
+    }
+
+    // the first bucket holds the authoritative state, all buckets are flagged for readers
+    pub fn get_migration_state(&self) -> u8 {
+        self.buckets[0].get_header_atomic().get_migration_state()
+    }
+
+    pub fn set_migration_state(&mut self, state: MigrationState) {
+        for bucket in self.buckets.iter_mut() {
+            bucket.header.set_migration_state(state);
+        }
+    }
+
+    pub fn try_claim_migration(&mut self) -> bool {
+        self.buckets[0]
+            .header
+            .try_set_migration_state(MigrationState::Pending, MigrationState::Running)
+    }
+
+    // give back a bucket group claimed by a crashed client, its items are moved again
+    pub fn reset_migration(&mut self) -> bool {
+        self.buckets[0]
+            .header
+            .try_set_migration_state(MigrationState::Running, MigrationState::Pending)
+    }
+
+    pub fn finish_migration(&mut self) {
+        // set the main bucket last, so that helpers only see it done after all
+        for bucket in self.buckets.iter_mut().rev() {
+            bucket.header.clear_migration_state();
+        }
+    }
 }
 
 pub struct Subtable {
@@ -376,4 +538,55 @@ impl Subtable {
     pub fn get_bucket_header_atomic(&self, bucket_group: usize, bucket: usize) -> Header {
         self.bucket_groups[bucket_group].buckets[bucket].get_header_atomic()
     }
+
+    pub fn get_non_empty_slot_num(&self) -> usize {
+        self.bucket_groups
+            .iter()
+            .map(|bucket_group| bucket_group.get_non_empty_slot_num())
+            .sum()
+    }
+
+    // estimated from every few bucket groups, the step is odd to visit every pattern of low bits,
+    // as the suffix of a subtable shows in the low bits of the bucket groups of its items
+    pub fn sample_non_empty_slot_num(&self, sample_group_num: usize) -> usize {
+        let step = (CONFIG.bucket_group_num / sample_group_num) | 1;
+        let sampled: usize = (0..sample_group_num)
+            .map(|i| {
+                self.bucket_groups[i * step % CONFIG.bucket_group_num].get_non_empty_slot_num()
+            })
+            .sum();
+        sampled * CONFIG.bucket_group_num / sample_group_num
+    }
+
+    pub fn get_capacity() -> usize {
+        CONFIG.bucket_group_num * CONFIG.bucket_num * CONFIG.slot_num
+    }
+
+    pub fn set_migration_state(&mut self, state: MigrationState) {
+        for bucket_group in self.bucket_groups.iter_mut() {
+            bucket_group.set_migration_state(state);
+        }
+    }
+
+    pub fn get_migration_state(&self, bucket_group: usize) -> u8 {
+        self.bucket_groups[bucket_group].get_migration_state()
+    }
+
+    pub fn try_claim_migration(&mut self, bucket_group: usize) -> bool {
+        self.bucket_groups[bucket_group].try_claim_migration()
+    }
+
+    pub fn reset_migration(&mut self, bucket_group: usize) -> bool {
+        self.bucket_groups[bucket_group].reset_migration()
+    }
+
+    pub fn finish_migration(&mut self, bucket_group: usize) {
+        self.bucket_groups[bucket_group].finish_migration()
+    }
+
+    pub fn is_migrating(&self) -> bool {
+        (0..CONFIG.bucket_group_num).any(|bucket_group| {
+            self.get_migration_state(bucket_group) != MigrationState::Done as u8
+        })
+    }
 }
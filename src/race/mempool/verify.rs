@@ -0,0 +1,384 @@
+use super::directory::MemPoolDirectory;
+use super::lease::LeaseTable;
+use super::subtable::{MigrationState, Slot, SlotPos, Subtable};
+use crate::cfg::config::CONFIG;
+use crate::race::common::utils::RaceUtils;
+use crate::race::computepool::directory::ClientEntry;
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    // a directory entry and a bucket header of its subtable disagree about the local depth
+    LocalDepth {
+        index: usize,
+        bucket_group: usize,
+        bucket: usize,
+        entry: u8,
+        header: u8,
+    },
+    // or about the suffix, the entry index restricted to its local depth
+    Suffix {
+        index: usize,
+        bucket_group: usize,
+        bucket: usize,
+        entry: u64,
+        header: u64,
+    },
+    // the key hashes to another subtable than the one of entry index
+    MisplacedKey {
+        index: usize,
+        key: String,
+    },
+    // the key is stored again in the subtable of entry index
+    DuplicateKey {
+        index: usize,
+        key: String,
+    },
+    // the kv block does not match its checksum, the key may be garbage
+    Checksum {
+        index: usize,
+        key: String,
+    },
+    // the length byte of the slot is not the size of its kv block
+    Length {
+        index: usize,
+        key: String,
+        slot: u8,
+        block: u8,
+    },
+    LockedEntry {
+        index: usize,
+        owner: u8,
+    },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::LocalDepth {
+                index,
+                bucket_group,
+                bucket,
+                entry,
+                header,
+            } => write!(
+                f,
+                "entry {} has local depth {}, bucket {}:{} has {}",
+                index, entry, bucket_group, bucket, header
+            ),
+            Violation::Suffix {
+                index,
+                bucket_group,
+                bucket,
+                entry,
+                header,
+            } => write!(
+                f,
+                "entry {} has suffix {}, bucket {}:{} has {}",
+                index, entry, bucket_group, bucket, header
+            ),
+            Violation::MisplacedKey { index, key } => {
+                write!(
+                    f,
+                    "key {} is misplaced in the subtable of entry {}",
+                    key, index
+                )
+            }
+            Violation::DuplicateKey { index, key } => {
+                write!(
+                    f,
+                    "key {} is duplicated in the subtable of entry {}",
+                    key, index
+                )
+            }
+            Violation::Checksum { index, key } => write!(
+                f,
+                "key {} in the subtable of entry {} fails its checksum",
+                key, index
+            ),
+            Violation::Length {
+                index,
+                key,
+                slot,
+                block,
+            } => write!(
+                f,
+                "key {} in the subtable of entry {} has length {}, its block has {}",
+                key, index, slot, block
+            ),
+            Violation::LockedEntry { index, owner } => {
+                write!(f, "entry {} is locked by client {}", index, owner)
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct VerifyReport {
+    pub violations: Vec<Violation>,
+    pub repaired_num: usize,
+    // items taken out of a wrong subtable by a repair, they are inserted again by a client
+    pub orphans: Vec<(String, String)>,
+}
+
+impl VerifyReport {
+    pub fn is_clean(&self) -> bool {
+        self.violations.is_empty()
+    }
+
+    pub fn append(&mut self, mut other: VerifyReport) {
+        self.violations.append(&mut other.violations);
+        self.repaired_num += other.repaired_num;
+        self.orphans.append(&mut other.orphans);
+    }
+}
+
+// walks the directory and every subtable, the directory is taken as the truth; a repair
+// clears bad slots without freeing their kv blocks, which a stale copy of a migration may
+// share, so that a block is rather leaked than freed twice
+pub struct Verifier<'a> {
+    dir: &'a MemPoolDirectory,
+    leases: &'a LeaseTable,
+    repair: bool,
+    // the repairing client, it holds the locks of all entries
+    owner: u8,
+    report: VerifyReport,
+}
+
+impl<'a> Verifier<'a> {
+    // an owner of 0 only checks
+    pub fn new(dir: &'a MemPoolDirectory, leases: &'a LeaseTable, owner: u8) -> Self {
+        Verifier {
+            dir,
+            leases,
+            repair: owner != 0,
+            owner,
+            report: VerifyReport::default(),
+        }
+    }
+
+    pub fn run(mut self) -> VerifyReport {
+        let size = RaceUtils::depth_to_size(self.dir.get_global_depth());
+        // the first entry of every subtable, the entry at its own suffix unless broken
+        let mut first_indexes: HashMap<u64, usize> = HashMap::new();
+        for index in 0..size {
+            self.verify_lock(index);
+            let entry = self.dir.get_entry_const(index);
+            let pointer = entry.get_subtable_pointer();
+            let first_index = *first_indexes.entry(pointer).or_insert(index);
+            if first_index == index {
+                self.verify_headers(index);
+                self.verify_slots(index);
+            } else {
+                self.verify_entry(index, first_index);
+            }
+        }
+        self.report
+    }
+
+    // a lock nobody holds a lease for keeps a repair from taking the locks of all entries, it is
+    // released first
+    pub fn run_locks(mut self) -> VerifyReport {
+        for index in 0..RaceUtils::depth_to_size(self.dir.get_global_depth()) {
+            self.verify_lock(index);
+        }
+        self.report
+    }
+
+    fn verify_lock(&mut self, index: usize) {
+        let data = self.dir.read_entry(index);
+        let owner = RaceUtils::get_lock_owner(data);
+        if owner == 0 || owner == self.owner {
+            return;
+        }
+        self.report
+            .violations
+            .push(Violation::LockedEntry { index, owner });
+        // a held lease is a rehash in progress or a crash to recover, only a lock without any
+        // lease is left forever
+        if self.repair
+            && !self.leases.is_held(owner as usize)
+            && self.dir.try_unlock_entry(index, data).is_ok()
+        {
+            self.report.repaired_num += 1;
+        }
+    }
+
+    // another entry of the subtable of first index, a repair gives it the local depth of the
+    // first, but an entry of another suffix can not be told where to point
+    fn verify_entry(&mut self, index: usize, first_index: usize) {
+        let first = self.dir.get_entry_const(first_index);
+        let local_depth = first.get_local_depth();
+        let subtable = first.get_subtable_pointer() as *const Subtable;
+        let header = unsafe { (*subtable).get_bucket_header_atomic(0, 0) };
+        let entry_depth = self.dir.get_entry_const(index).get_local_depth();
+        if entry_depth != local_depth {
+            self.report.violations.push(Violation::LocalDepth {
+                index,
+                bucket_group: 0,
+                bucket: 0,
+                entry: entry_depth,
+                header: header.get_local_depth(),
+            });
+            if self.repair {
+                let old_data = self.dir.read_entry(index);
+                let mut entry = ClientEntry { data: old_data };
+                entry.set_local_depth(local_depth);
+                if self.dir.update_entry(index, old_data, entry.get_data()) {
+                    self.report.repaired_num += 1;
+                }
+            }
+        } else if RaceUtils::restrict_suffix_to(index as u64, local_depth) != header.get_suffix() {
+            self.report.violations.push(Violation::Suffix {
+                index,
+                bucket_group: 0,
+                bucket: 0,
+                entry: RaceUtils::restrict_suffix_to(index as u64, local_depth),
+                header: header.get_suffix(),
+            });
+        }
+    }
+
+    // only the first bad bucket of a subtable is reported, a repair rewrites all of them
+    fn verify_headers(&mut self, index: usize) {
+        let entry = self.dir.get_entry_const(index);
+        let local_depth = entry.get_local_depth();
+        let suffix = RaceUtils::restrict_suffix_to(index as u64, local_depth);
+        let subtable = entry.get_subtable_pointer() as *const Subtable;
+        for bucket_group in 0..CONFIG.bucket_group_num {
+            for bucket in 0..CONFIG.bucket_num {
+                let header = unsafe { (*subtable).get_bucket_header_atomic(bucket_group, bucket) };
+                let violation = if header.get_local_depth() != local_depth {
+                    Violation::LocalDepth {
+                        index,
+                        bucket_group,
+                        bucket,
+                        entry: local_depth,
+                        header: header.get_local_depth(),
+                    }
+                } else if header.get_suffix() != suffix {
+                    Violation::Suffix {
+                        index,
+                        bucket_group,
+                        bucket,
+                        entry: suffix,
+                        header: header.get_suffix(),
+                    }
+                } else {
+                    continue;
+                };
+                self.report.violations.push(violation);
+                if self.repair {
+                    self.dir.set_subtable_header(index, local_depth, suffix);
+                    self.report.repaired_num += 1;
+                }
+                return;
+            }
+        }
+    }
+
+    fn verify_slots(&mut self, index: usize) {
+        let entry = self.dir.get_entry_const(index);
+        let local_depth = entry.get_local_depth();
+        let suffix = RaceUtils::restrict_suffix_to(index as u64, local_depth);
+        let subtable = entry.get_subtable_pointer() as *const Subtable;
+        let mut keys = HashMap::new();
+        for bucket_group in 0..CONFIG.bucket_group_num {
+            if self.is_migrating(index, bucket_group) {
+                continue;
+            }
+            for bucket in 0..CONFIG.bucket_num {
+                for slot in 0..CONFIG.slot_num {
+                    let slot_pos = SlotPos {
+                        subtable,
+                        bucket_group,
+                        bucket,
+                        header: 0,
+                        slot,
+                    };
+                    let slot = Slot {
+                        data: unsafe { (*subtable).get(&slot_pos) },
+                    };
+                    if slot.judge_empty() {
+                        continue;
+                    }
+                    let data = slot.get_data();
+                    let kv_pointer = slot.get_kv_pointer();
+                    // an inline item is read from the slot itself
+                    let kv = if slot.is_inline() {
+                        slot.get_kv()
+                    } else {
+                        RaceUtils::get_kvblock_from_pointer(kv_pointer)
+                    };
+                    let kv = match kv {
+                        Some(kv) => kv,
+                        None => continue,
+                    };
+                    if !RaceUtils::check_crc(&kv.key, &kv.value, kv.crc64) {
+                        self.report
+                            .violations
+                            .push(Violation::Checksum { index, key: kv.key });
+                        self.clear_slot(&slot_pos, data);
+                    } else if RaceUtils::get_suffix(&kv.key, local_depth) != suffix {
+                        self.report.violations.push(Violation::MisplacedKey {
+                            index,
+                            key: kv.key.clone(),
+                        });
+                        if self.clear_slot(&slot_pos, data) {
+                            self.report.orphans.push((kv.key, kv.value));
+                        }
+                    } else if keys.insert(kv.key.clone(), ()).is_some() {
+                        // the first copy is kept
+                        self.report
+                            .violations
+                            .push(Violation::DuplicateKey { index, key: kv.key });
+                        self.clear_slot(&slot_pos, data);
+                    } else if !slot.is_inline() {
+                        let expected = Slot {
+                            data: RaceUtils::set_data(&kv.key, &kv.value, kv_pointer as u64),
+                        };
+                        if expected.get_length() != slot.get_length() {
+                            self.report.violations.push(Violation::Length {
+                                index,
+                                key: kv.key,
+                                slot: slot.get_length(),
+                                block: expected.get_length(),
+                            });
+                            self.write_slot(&slot_pos, expected.get_data(), data);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // a bucket group of an unfinished split holds items of both suffixes in the old subtable,
+    // and copies of them in the new one, it is left to the migration
+    fn is_migrating(&self, index: usize, bucket_group: usize) -> bool {
+        let local_depth = self.dir.get_entry_const(index).get_local_depth();
+        if local_depth == 0 {
+            return false;
+        }
+        let sibling = index ^ (1 << (local_depth - 1));
+        [index, sibling].iter().any(|index| {
+            let subtable =
+                self.dir.get_entry_const(*index).get_subtable_pointer() as *const Subtable;
+            unsafe { (*subtable).get_migration_state(bucket_group) != MigrationState::Done as u8 }
+        })
+    }
+
+    fn clear_slot(&mut self, slot_pos: &SlotPos, data: u64) -> bool {
+        self.write_slot(slot_pos, 0, data)
+    }
+
+    fn write_slot(&mut self, slot_pos: &SlotPos, data: u64, old: u64) -> bool {
+        if self.repair
+            && unsafe { (*(slot_pos.subtable as *mut Subtable)).set(slot_pos, data, old) }
+        {
+            self.report.repaired_num += 1;
+            return true;
+        }
+        false
+    }
+}